@@ -0,0 +1,110 @@
+//! Named read cursors that leave the entries in place.
+
+use leveldb::database::batch::Writebatch;
+use leveldb::database::kv::KV;
+use leveldb::options::ReadOptions;
+
+use super::{decode_u64, encode_u64, fnv1a, Error, Id, Journal, Key, KeyType};
+
+/// A reader with its own position in the journal, stored under its name so
+/// it picks up where it left off after a restart. Reading through a cursor
+/// never removes anything, popping and acking carry on as if it was not
+/// there, and a cursor that falls behind them skips ahead to the oldest
+/// entry still stored.
+///
+/// Positions are ids, so `defragment` renumbering the entries throws
+/// cursors off.
+pub struct Cursor<'a> {
+  journal: &'a Journal,
+  name: String,
+  key: Key,
+  position: Id
+}
+
+impl Journal {
+  /// Opens the cursor called `name`, at the oldest stored entry if it was
+  /// never used before. Two names with the same hash share a slot, the one
+  /// last advanced wins and the other starts over.
+  pub fn subscribe(&self, name: &str) -> Result<Cursor<'_>, Error> {
+    let key = Key::new(KeyType::Cursor, fnv1a(name.as_bytes()));
+    let read_options = ReadOptions::new();
+    let position = match self.db.get(read_options, key)? {
+      Some(ref data) if data.len() >= 8 && &data[8..] == name.as_bytes() => decode_u64(&data[..8]).unwrap_or(0),
+      _ => 0
+    };
+    Ok(Cursor { journal: self, name: name.to_string(), key, position })
+  }
+}
+
+impl<'a> Cursor<'a> {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// The id the next read starts at.
+  pub fn position(&self) -> Id {
+    self.position
+  }
+}
+
+impl<'a> Iterator for Cursor<'a> {
+  type Item = Result<(Id, Vec<u8>), Error>;
+
+  /// Reads the next entry and stores the position after it.
+  fn next(&mut self) -> Option<Self::Item> {
+    let journal = self.journal;
+    let from = self.position.max(journal.reserved_tail.id);
+    let (key, stored) = Journal::entries_in(&journal.db, KeyType::Queue, from, Some(journal.head.id)).next()?;
+    let data = match journal.decode_value(key.id, stored) {
+      Ok(data) => data,
+      Err(err) => return Some(Err(err))
+    };
+    let mut record = encode_u64(key.id + 1).to_vec();
+    record.extend_from_slice(self.name.as_bytes());
+    let mut batch = Writebatch::new();
+    batch.put(self.key, &record);
+    if let Err(err) = journal.write(&batch) {
+      return Some(Err(err))
+    }
+    self.position = key.id + 1;
+    Some(Ok((key.id, data)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use self::tempdir::TempDir;
+  use super::super::Journal;
+
+  #[test]
+  fn test_cursors_resume() {
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      let mut journal = Journal::open(dir.path()).unwrap();
+      for i in 0..5u8 {
+        journal.push(&[i]).unwrap();
+      }
+      let mut first = journal.subscribe("first").unwrap();
+      assert_eq!((0, vec![0u8]), first.next().unwrap().unwrap());
+      assert_eq!((1, vec![1u8]), first.next().unwrap().unwrap());
+      assert_eq!((2, vec![2u8]), first.next().unwrap().unwrap());
+      let mut second = journal.subscribe("second").unwrap();
+      assert_eq!((0, vec![0u8]), second.next().unwrap().unwrap());
+    }
+
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(3, journal.subscribe("first").unwrap().position());
+    let rest: Vec<_> = journal.subscribe("first").unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(vec![3, 4], rest);
+    assert_eq!((1, vec![1u8]), journal.subscribe("second").unwrap().next().unwrap().unwrap());
+
+    // reading through cursors left everything in place
+    assert_eq!(5, journal.len());
+    assert_eq!(Some(vec![0u8]), journal.pop().unwrap());
+    journal.pop().unwrap();
+    journal.pop().unwrap();
+    assert_eq!((3, vec![3u8]), journal.subscribe("third").unwrap().next().unwrap().unwrap());
+  }
+}
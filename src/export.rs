@@ -0,0 +1,223 @@
+//! A flat file format for moving journals between machines.
+//!
+//! An export is the magic bytes `DJEXPRT1`, followed by one record per
+//! entry: the id and the value length as 8 byte big-endian integers, then
+//! the value itself. Values are written as plaintext, whatever cipher the
+//! journal was opened with.
+
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::{Error, Id, Journal, JournalOptions, KeyType};
+
+pub const EXPORT_MAGIC: &[u8; 8] = b"DJEXPRT1";
+
+/// How many entries `import` writes at once.
+const IMPORT_BATCH: usize = 1024;
+
+impl Journal {
+  /// Writes every entry that has not been acked yet, oldest first, to
+  /// `writer` and returns how many were written. Reserved entries are
+  /// exported too, so they are handed out again after an import just like
+  /// after a restart.
+  pub fn export(&self, mut writer: impl Write) -> Result<u64, Error> {
+    writer.write_all(EXPORT_MAGIC)?;
+    let mut count = 0;
+    for (key, stored) in Journal::entries_in(&self.db, KeyType::Queue, self.reserved_tail.id, Some(self.head.id)) {
+      let data = self.decode_value(key.id, stored)?;
+      writer.write_all(&key.id.to_be_bytes())?;
+      writer.write_all(&(data.len() as u64).to_be_bytes())?;
+      writer.write_all(&data)?;
+      count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+  }
+
+  /// Creates a new journal at `path` holding the entries of an export,
+  /// under their original ids and in their original order.
+  ///
+  /// Fails if `path` already exists. A stream with a bad header, ids out of
+  /// order or a truncated record fails the import with `Error::Io` and
+  /// removes the partially imported journal again.
+  pub fn import(path: &Path, reader: impl Read) -> Result<Journal, Error> {
+    Journal::import_with_options(path, JournalOptions::new(), reader)
+  }
+
+  /// Like `import`, but creates the journal with `options`, so values are
+  /// encrypted or offloaded like in the journal they were exported from.
+  /// Entries are written in batches of a thousand or so.
+  pub fn import_with_options(path: &Path, options: JournalOptions, reader: impl Read) -> Result<Journal, Error> {
+    if path.exists() {
+      let message = format!("import target {} already exists", path.display());
+      return Err(Error::Io(io::Error::new(io::ErrorKind::AlreadyExists, message)))
+    }
+
+    let mut journal = Journal::open_with_options(path, options)?;
+    match journal.import_entries(reader) {
+      Ok(()) => Ok(journal),
+      Err(err) => {
+        drop(journal);
+        fs::remove_dir_all(path)?;
+        Err(err)
+      }
+    }
+  }
+
+  fn import_entries(&mut self, mut reader: impl Read) -> Result<(), Error> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != EXPORT_MAGIC {
+      return Err(invalid_data("not a journal export"))
+    }
+
+    let mut pending: Vec<(Id, Vec<u8>)> = Vec::new();
+    let mut last = None;
+    while let Some((id, data)) = read_record(&mut reader)? {
+      match last {
+        None => {
+          self.head.id = id;
+          self.tail.id = id;
+          self.reserved_tail.id = id;
+        },
+        Some(last) if id <= last => return Err(invalid_data("export ids are out of order")),
+        _ => ()
+      }
+      last = Some(id);
+      pending.push((id, data));
+      if pending.len() == IMPORT_BATCH {
+        self.import_batch(&pending)?;
+        pending.clear();
+      }
+    }
+    self.import_batch(&pending)
+  }
+
+  fn import_batch(&mut self, entries: &[(Id, Vec<u8>)]) -> Result<(), Error> {
+    let entries: Vec<(Id, &[u8])> = entries.iter().map(|&(id, ref data)| (id, &data[..])).collect();
+    self.push_at(&entries)
+  }
+}
+
+fn invalid_data(message: &str) -> Error {
+  Error::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+// Reads one record, `None` if the stream ended cleanly before it.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<(Id, Vec<u8>)>> {
+  let mut header = [0u8; 16];
+  let mut filled = 0;
+  while filled < header.len() {
+    match reader.read(&mut header[filled..]) {
+      Ok(0) if filled == 0 => return Ok(None),
+      Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated export record")),
+      Ok(n) => filled += n,
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+      Err(err) => return Err(err)
+    }
+  }
+
+  let mut id = [0u8; 8];
+  let mut len = [0u8; 8];
+  id.copy_from_slice(&header[0..8]);
+  len.copy_from_slice(&header[8..16]);
+  let len = u64::from_be_bytes(len);
+
+  let mut data = Vec::new();
+  reader.take(len).read_to_end(&mut data)?;
+  if (data.len() as u64) < len {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated export record"))
+  }
+  Ok(Some((u64::from_be_bytes(id), data)))
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use std::io;
+  use self::tempdir::TempDir;
+  use super::EXPORT_MAGIC;
+  use super::super::{Error, Journal};
+
+  #[test]
+  fn test_export() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8, 3u8]).unwrap();
+    journal.push(&[4u8]).unwrap();
+    journal.pop().unwrap();
+
+    let mut out = Vec::new();
+    assert_eq!(2, journal.export(&mut out).unwrap());
+
+    let mut expected = EXPORT_MAGIC.to_vec();
+    expected.extend_from_slice(&1u64.to_be_bytes());
+    expected.extend_from_slice(&2u64.to_be_bytes());
+    expected.extend_from_slice(&[2u8, 3u8]);
+    expected.extend_from_slice(&2u64.to_be_bytes());
+    expected.extend_from_slice(&1u64.to_be_bytes());
+    expected.extend_from_slice(&[4u8]);
+    assert_eq!(expected, out);
+  }
+
+  #[test]
+  fn test_import() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(&dir.path().join("original")).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    journal.push(&[3u8, 4u8]).unwrap();
+    journal.push(&[5u8]).unwrap();
+    journal.pop().unwrap();
+
+    let mut out = Vec::new();
+    journal.export(&mut out).unwrap();
+
+    let mut imported = Journal::import(&dir.path().join("imported"), &out[..]).unwrap();
+    assert_eq!(journal.iter().map(Result::unwrap).collect::<Vec<_>>(), imported.iter().map(Result::unwrap).collect::<Vec<_>>());
+    assert_eq!(4, imported.push(&[6u8]).unwrap());
+    drop(imported);
+
+    let imported = Journal::open(&dir.path().join("imported")).unwrap();
+    assert_eq!(vec![(1, vec![2u8]), (2, vec![3u8, 4u8]), (3, vec![5u8]), (4, vec![6u8])],
+               imported.iter().map(Result::unwrap).collect::<Vec<_>>());
+
+    let truncated = dir.path().join("truncated");
+    match Journal::import(&truncated, &out[..out.len() - 1]) {
+      Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+      other => panic!("expected a truncation error, got {:?}", other.err())
+    }
+    assert!(!truncated.exists());
+    assert!(Journal::import(&dir.path().join("garbage"), &b"not an export"[..]).is_err());
+  }
+
+  #[test]
+  fn test_import_with_options() {
+    use std::fs;
+    use super::super::{JournalOptions, OFFLOAD_FILE};
+
+    // more records than fit into one batch, with a gap in the ids
+    let mut export = EXPORT_MAGIC.to_vec();
+    let ids: Vec<u64> = (0..1500).chain(1600..2000).collect();
+    for &id in ids.iter() {
+      let value = if id == 1999 { vec![9u8; 100] } else { vec![id as u8] };
+      export.extend_from_slice(&id.to_be_bytes());
+      export.extend_from_slice(&(value.len() as u64).to_be_bytes());
+      export.extend_from_slice(&value);
+    }
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.offload_threshold = Some(50);
+    let path = dir.path().join("imported");
+    let journal = Journal::import_with_options(&path, options, &export[..]).unwrap();
+    assert_eq!(ids, journal.iter().map(Result::unwrap).map(|(id, _)| id).collect::<Vec<_>>());
+    assert_eq!(Some(vec![9u8; 100]), journal.get(1999).unwrap());
+    assert_eq!(100, fs::metadata(path.join(OFFLOAD_FILE)).unwrap().len());
+    assert_eq!(2000, journal.next_id());
+  }
+}
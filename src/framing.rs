@@ -0,0 +1,180 @@
+//! Piping entries to and from other programs as a plain byte stream.
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use super::{Error, Journal};
+
+/// How entries are separated in a stream.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Framing {
+  /// Every entry is preceded by its length as an 8 byte big-endian integer.
+  LengthPrefixed,
+  /// Every entry is followed by a newline, for text based tools. Entries
+  /// containing a newline cannot be framed like this.
+  Newline
+}
+
+impl Journal {
+  /// Writes every entry ready to be popped, oldest first, to `sink` and
+  /// returns how many were written. Nothing is consumed.
+  ///
+  /// With `Framing::Newline`, an entry containing a newline fails this with
+  /// an `Error::Io` of kind `InvalidInput`, after the entries before it were
+  /// written.
+  pub fn write_all_to(&self, mut sink: impl Write, framing: Framing) -> Result<u64, Error> {
+    let mut count = 0;
+    for (key, stored) in self.ready() {
+      let data = self.decode_value(key.id, stored)?;
+      match framing {
+        Framing::LengthPrefixed => {
+          sink.write_all(&(data.len() as u64).to_be_bytes())?;
+          sink.write_all(&data)?;
+        },
+        Framing::Newline => {
+          if data.contains(&b'\n') {
+            let message = format!("entry {} contains a newline", key.id);
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message)))
+          }
+          sink.write_all(&data)?;
+          sink.write_all(b"\n")?;
+        }
+      }
+      count += 1;
+    }
+    sink.flush()?;
+    Ok(count)
+  }
+
+  /// Pushes every entry framed in `source` and returns how many there were.
+  /// All of them are pushed in a single write, so this holds the whole
+  /// input in memory.
+  ///
+  /// A stream ending in the middle of a frame, or in an entry without its
+  /// newline, fails with an `Error::Io` of kind `UnexpectedEof` and pushes
+  /// nothing.
+  pub fn read_all_from(&mut self, source: impl Read, framing: Framing) -> Result<u64, Error> {
+    let mut source = BufReader::new(source);
+    let mut entries = Vec::new();
+    match framing {
+      Framing::LengthPrefixed => {
+        while let Some(len) = read_length(&mut source)? {
+          let mut data = Vec::new();
+          (&mut source).take(len).read_to_end(&mut data)?;
+          if (data.len() as u64) < len {
+            return Err(truncated())
+          }
+          entries.push(data);
+        }
+      },
+      Framing::Newline => {
+        loop {
+          let mut data = Vec::new();
+          if source.read_until(b'\n', &mut data)? == 0 {
+            break
+          }
+          if data.pop() != Some(b'\n') {
+            return Err(truncated())
+          }
+          entries.push(data);
+        }
+      }
+    }
+    self.push_all(&entries)?;
+    Ok(entries.len() as u64)
+  }
+}
+
+fn truncated() -> Error {
+  Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame"))
+}
+
+// Reads a length prefix, `None` if the stream ended cleanly before it.
+fn read_length(reader: &mut impl Read) -> Result<Option<u64>, Error> {
+  let mut len = [0u8; 8];
+  let mut filled = 0;
+  while filled < len.len() {
+    match reader.read(&mut len[filled..]) {
+      Ok(0) if filled == 0 => return Ok(None),
+      Ok(0) => return Err(truncated()),
+      Ok(n) => filled += n,
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+      Err(err) => return Err(Error::Io(err))
+    }
+  }
+  Ok(Some(u64::from_be_bytes(len)))
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use std::io;
+  use self::tempdir::TempDir;
+  use super::Framing;
+  use super::super::{Error, Journal};
+
+  #[test]
+  fn test_write_all_to() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(b"gone").unwrap();
+    journal.push(b"first").unwrap();
+    journal.push(b"").unwrap();
+    journal.push(b"third\nline").unwrap();
+    journal.pop().unwrap();
+
+    let mut sink = Vec::new();
+    assert_eq!(3, journal.write_all_to(&mut sink, Framing::LengthPrefixed).unwrap());
+    let mut entries = Vec::new();
+    let mut rest = &sink[..];
+    while !rest.is_empty() {
+      let mut len = [0u8; 8];
+      len.copy_from_slice(&rest[..8]);
+      let len = u64::from_be_bytes(len) as usize;
+      entries.push(rest[8..8 + len].to_vec());
+      rest = &rest[8 + len..];
+    }
+    assert_eq!(vec![b"first".to_vec(), b"".to_vec(), b"third\nline".to_vec()], entries);
+    assert_eq!(3, journal.len());
+
+    let mut sink = Vec::new();
+    match journal.write_all_to(&mut sink, Framing::Newline) {
+      Err(Error::Io(_)) => (),
+      other => panic!("expected Error::Io, got {:?}", other)
+    }
+    assert_eq!(b"first\n\n".to_vec(), sink);
+  }
+
+  #[test]
+  fn test_read_all_from() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(b"zero").unwrap();
+    assert_eq!(3, journal.read_all_from(&b"one\n\nthree\n"[..], Framing::Newline).unwrap());
+    assert_eq!(0, journal.read_all_from(&b""[..], Framing::Newline).unwrap());
+    let expected: Vec<&[u8]> = vec![b"zero", b"one", b"", b"three"];
+    let entries: Vec<Vec<u8>> = journal.iter().map(Result::unwrap).map(|(_, data)| data).collect();
+    assert_eq!(expected, entries);
+
+    match journal.read_all_from(&b"four\nfi"[..], Framing::Newline) {
+      Err(Error::Io(ref err)) => assert_eq!(io::ErrorKind::UnexpectedEof, err.kind()),
+      other => panic!("expected a truncated frame, got {:?}", other)
+    }
+    let mut framed = Vec::new();
+    journal.write_all_to(&mut framed, Framing::LengthPrefixed).unwrap();
+    framed.pop();
+    match journal.read_all_from(&framed[..], Framing::LengthPrefixed) {
+      Err(Error::Io(ref err)) => assert_eq!(io::ErrorKind::UnexpectedEof, err.kind()),
+      other => panic!("expected a truncated frame, got {:?}", other)
+    }
+    assert_eq!(4, journal.len());
+
+    let other_dir = TempDir::new("journal_test").unwrap();
+    let mut other = Journal::open(other_dir.path()).unwrap();
+    framed.clear();
+    journal.write_all_to(&mut framed, Framing::LengthPrefixed).unwrap();
+    assert_eq!(4, other.read_all_from(&framed[..], Framing::LengthPrefixed).unwrap());
+    assert!(journal.contents_eq(&other).unwrap());
+  }
+}
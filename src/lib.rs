@@ -1,28 +1,170 @@
 extern crate leveldb;
 extern crate db_key as key;
 
+pub use typed::{Encode,Decode};
+pub use export::EXPORT_MAGIC;
+pub use shared::{SharedJournal,SharedGuard,SharedReadGuard};
+pub use cursor::Cursor;
+pub use framing::Framing;
+pub use metrics::JournalMetrics;
+pub use reservations::{Reservations,ReservedEntry};
+pub use segments::SegmentedJournal;
+
 use leveldb::database::Database;
 use leveldb::database::kv::KV;
-use leveldb::database::error::Error;
+use leveldb::database::error::Error as DbError;
 use leveldb::database::comparator::{OrdComparator};
-use leveldb::database::iterator::{Iterable};
+use leveldb::database::iterator::{Iterable,LevelDBIterator,KeyIterator};
+use leveldb::database::iterator::Iterator as DbIterator;
+use leveldb::database::batch::{Batch,Writebatch};
+use leveldb::database::compaction::Compaction;
+use leveldb::database::snapshots::{Snapshot,Snapshots};
 use leveldb::options::{Options,WriteOptions,ReadOptions};
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::path::Path;
+use std::collections::BTreeSet;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read,Seek,SeekFrom,Write};
+use std::mem;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{channel,Receiver,Sender};
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize,Ordering as AtomicOrdering};
+use std::thread;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
 
 #[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Clone,Copy)]
 #[repr(u64)]
 pub enum KeyType {
+  /// One record per entry, holding its value.
   Queue,
-  Chunk
+  /// Unused, nothing splits values into chunks.
+  Chunk,
+  Delivery,
+  Meta,
+  Timestamp,
+  Index,
+  IndexKey,
+  Offloaded,
+  Cursor,
+  UserMeta,
+  Pinned,
+  /// What keys of an unknown key type decode to. Sorts after every
+  /// keyspace, so it has to stay last.
+  Unknown
+}
+
+impl KeyType {
+  fn from_u64(value: u64) -> Option<KeyType> {
+    match value {
+      0 => Some(KeyType::Queue),
+      1 => Some(KeyType::Chunk),
+      2 => Some(KeyType::Delivery),
+      3 => Some(KeyType::Meta),
+      4 => Some(KeyType::Timestamp),
+      5 => Some(KeyType::Index),
+      6 => Some(KeyType::IndexKey),
+      7 => Some(KeyType::Offloaded),
+      8 => Some(KeyType::Cursor),
+      9 => Some(KeyType::UserMeta),
+      10 => Some(KeyType::Pinned),
+      _ => None
+    }
+  }
 }
 
 pub type Id = u64;
 
+/// LevelDB reads the comparator name as a C string, so it has to carry
+/// its terminator.
+const COMPARATOR_NAME: &str = "journal-comparator\0";
+
+/// The comparator journals are opened with, sorting by keyspace, then id.
+///
+/// LevelDB refuses to open a database with a comparator other than the one
+/// it was created with, so anything else opening a journal's database
+/// directly has to use this one.
+pub fn journal_comparator() -> OrdComparator<Key> {
+  OrdComparator::new(COMPARATOR_NAME)
+}
+
+/// The keyspaces holding per-entry records next to a `KeyType::Queue` entry,
+/// under the same id. They move and vanish together with the entry.
+const ENTRY_ATTRIBUTES: [KeyType; 5] = [KeyType::Delivery, KeyType::Timestamp, KeyType::IndexKey, KeyType::Offloaded, KeyType::Pinned];
+
+/// The file in the journal's directory that large values are offloaded to,
+/// see `JournalOptions::offload_threshold`.
+const OFFLOAD_FILE: &str = "offloaded-values";
+
+/// Name of the file written and synced on open to find out whether the
+/// filesystem supports fsync. Removed again right away.
+const SYNC_PROBE_FILE: &str = "sync-probe";
+
+/// Where the index keeps the id of the entry pushed with `user_key`.
+///
+/// `KeyType::Index` records are keyed by a hash of the user key, the user
+/// key itself is an entry attribute under `KeyType::IndexKey`, which is what
+/// lookups check to tell colliding keys apart.
+fn index_key(user_key: &[u8]) -> Key {
+  Key::new(KeyType::Index, fnv1a(user_key))
+}
+
+/// FNV-1a, stable across platforms and releases, unlike std's hashers.
+fn fnv1a(data: &[u8]) -> u64 {
+  data.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+    (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+  })
+}
+
+/// Id of the `KeyType::Meta` record holding the head cursor.
+const HEAD_META: Id = 0;
+
+/// Id of the `KeyType::Meta` record marking a `defragment` in progress.
+const DEFRAGMENT_META: Id = 1;
+
+/// Id of the `KeyType::Meta` record holding the on-disk format version.
+const FORMAT_META: Id = 2;
+
+/// Id of the `KeyType::Meta` record counting the entries ever pushed.
+const PUSHED_META: Id = 3;
+
+/// Id of the `KeyType::Meta` record counting the entries ever popped.
+const POPPED_META: Id = 4;
+
+/// Id of the `KeyType::Meta` record holding the tail cursor while popped
+/// entries wait to be deleted, see `JournalOptions::deferred_deletes`.
+const TAIL_META: Id = 5;
+
+/// Id of the `KeyType::Meta` record `Journal::health_check` writes and
+/// deletes again.
+const HEALTH_META: Id = 6;
+
+/// Id of the `KeyType::Meta` record counting the entries stored, popped
+/// ones waiting for `delete_deferred` included.
+const STORED_META: Id = 7;
+
+/// Id of the `KeyType::Meta` record holding how many entries of the oldest
+/// segment a `SegmentedJournal` popped.
+const SEGMENT_META: Id = 8;
+
+/// The on-disk format written by this version of the crate.
+///
+/// 1: journals written before the format was versioned, without the head
+///    cursor record. Still readable, `Journal::migrate` upgrades them.
+/// 2: the head cursor is kept in `KeyType::Meta`.
+pub const FORMAT_VERSION: u64 = 2;
+
+/// Keys are stored as this struct's memory, native-endian. `repr(C)` pins
+/// the layout existing journals were written with: the key type, then the id.
 #[derive(Debug,PartialEq,Eq,Clone,Copy)]
+#[repr(C)]
 pub struct Key {
-  id: Id,
   keytype: KeyType,
+  id: Id,
 }
 
 impl Key {
@@ -31,47 +173,44 @@ impl Key {
   }
 
   pub fn new(keytype: KeyType, id: Id) -> Key {
-    Key { keytype: keytype, id: id }
+    Key { keytype, id }
   }
 }
 
 impl key::Key for Key {
+  // LevelDB calls this from its comparator callback in C, where a panic
+  // can not unwind through, and a transmute of foreign bytes could produce
+  // a key type that does not exist. So anything decodes: missing bytes are
+  // zero, extra bytes ignored, and unknown key types sort last, as
+  // `KeyType::Unknown`.
   fn from_u8(key: &[u8]) -> Key {
-    use std::mem::transmute;
-
-    assert!(key.len() == 16);
-    let mut result: [u8; 16] = [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0];
-
-    for (i, val) in key.iter().enumerate() {
-      result[i] = *val;
+    let mut keytype = [0u8; 8];
+    let mut id = [0u8; 8];
+    for (i, val) in key.iter().take(16).enumerate() {
+      if i < 8 {
+        keytype[i] = *val;
+      } else {
+        id[i - 8] = *val;
+      }
     }
 
-    unsafe { transmute(result) }
+    match KeyType::from_u64(u64::from_ne_bytes(keytype)) {
+      Some(keytype) => Key { keytype, id: u64::from_ne_bytes(id) },
+      None => Key { keytype: KeyType::Unknown, id: u64::from_ne_bytes(id) }
+    }
   }
 
   fn as_slice<T, F: Fn(&[u8]) -> T>(&self, f: F) -> T {
     use std::mem::transmute;
 
-    let val = unsafe { transmute::<_, &[u8; 16]>(self) };
+    let val = unsafe { transmute::<&Key, &[u8; 16]>(self) };
     f(val)
   }
 }
 
 impl PartialOrd for Key {
   fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
-    if self.keytype < other.keytype {
-      return Some(Ordering::Less)
-    }
-    if self.keytype > other.keytype {
-      return Some(Ordering::Greater)
-    }
-    if self.id < other.id {
-      return Some(Ordering::Less)
-    }
-    if self.id > other.id {
-      return Some(Ordering::Greater)
-    }
-    None
+    Some(self.cmp(other))
   }
 }
 
@@ -93,191 +232,5098 @@ impl Ord for Key {
   }
 }
 
+mod typed;
+mod export;
+mod sha256;
+mod shared;
+mod cursor;
+mod framing;
+mod metrics;
+mod reservations;
+mod segments;
+
+/// Errors returned by the journal.
+#[derive(Debug)]
+pub enum Error {
+  /// LevelDB failed.
+  Database(DbError),
+  /// Moving or copying journal files failed.
+  Io(io::Error),
+  /// The head reached the end of the id space, see `Journal::compact_ids`.
+  IdSpaceExhausted,
+  /// The entry with this id could not be decrypted. It was either tampered
+  /// with or the journal was opened with the wrong key.
+  Decryption(Id),
+  /// The entry with this id could not be decoded into the requested type.
+  Decode(Id, Box<dyn error::Error + Send + Sync>),
+  /// The database at this path is held open by another handle or process.
+  Locked(PathBuf),
+  /// A value of this many bytes exceeds `JournalOptions::max_value_size`.
+  ValueTooLarge(usize),
+  /// LevelDB can not open a database at this path, for the reason given.
+  InvalidPath(PathBuf, &'static str),
+  /// The journal was written in this format version, which is newer than
+  /// `FORMAT_VERSION`.
+  UnsupportedFormat(u64),
+  /// The journal holds `JournalOptions::capacity` entries already.
+  Full,
+  /// Waiting for the journal gave up, see `SharedJournal::push_blocking`.
+  TimedOut,
+  /// The entry with this id is not in flight.
+  NotReserved(Id),
+  /// The journal at this path holds entries but no stored head cursor, see
+  /// `JournalOptions::require_cursors`.
+  MissingCursors(PathBuf)
+}
+
+impl Error {
+  // LevelDB only reports errors as messages, this is what its env writes
+  // when the LOCK file is held, either by another process or by another
+  // handle in this one.
+  fn from_open(path: &Path, err: DbError) -> Error {
+    let message = err.to_string();
+    if message.contains("IO error: lock ") && message.contains("LOCK") {
+      Error::Locked(path.to_path_buf())
+    } else {
+      Error::Database(err)
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::Database(ref err) => write!(f, "{}", err),
+      Error::Io(ref err) => write!(f, "I/O error: {}", err),
+      Error::IdSpaceExhausted => write!(f, "journal ran out of ids"),
+      Error::Decryption(id) => write!(f, "entry {} could not be decrypted", id),
+      Error::Decode(id, ref err) => write!(f, "entry {} could not be decoded: {}", id, err),
+      Error::Locked(ref path) => write!(f, "journal at {} is locked by another handle", path.display()),
+      Error::ValueTooLarge(size) => write!(f, "value of {} bytes exceeds the maximum value size", size),
+      Error::InvalidPath(ref path, reason) => write!(f, "can not open a journal at {}: {}", path.display(), reason),
+      Error::UnsupportedFormat(version) => write!(f, "journal format {} is newer than the supported format {}", version, FORMAT_VERSION),
+      Error::Full => write!(f, "journal is full"),
+      Error::TimedOut => write!(f, "timed out waiting for the journal"),
+      Error::NotReserved(id) => write!(f, "entry {} is not reserved", id),
+      Error::MissingCursors(ref path) => write!(f, "journal at {} has no stored head cursor", path.display())
+    }
+  }
+}
+
+impl error::Error for Error {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match *self {
+      Error::Database(ref err) => Some(err),
+      Error::Io(ref err) => Some(err),
+      Error::IdSpaceExhausted => None,
+      Error::Decryption(_) => None,
+      Error::Decode(_, ref err) => Some(&**err),
+      Error::Locked(_) => None,
+      Error::ValueTooLarge(_) => None,
+      Error::InvalidPath(..) => None,
+      Error::UnsupportedFormat(_) => None,
+      Error::Full => None,
+      Error::TimedOut => None,
+      Error::NotReserved(_) => None,
+      Error::MissingCursors(_) => None
+    }
+  }
+}
+
+impl From<DbError> for Error {
+  fn from(err: DbError) -> Error {
+    Error::Database(err)
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(err: io::Error) -> Error {
+    Error::Io(err)
+  }
+}
+
+/// Something a `Range` can pull a `Key` out of.
+trait RangeItem {
+  fn key(&self) -> &Key;
+}
+
+impl RangeItem for Key {
+  fn key(&self) -> &Key {
+    self
+  }
+}
+
+impl RangeItem for (Key, Vec<u8>) {
+  fn key(&self) -> &Key {
+    &self.0
+  }
+}
+
+/// Restricts a LevelDB iterator to the keys of a single `KeyType`, optionally
+/// below an upper id bound.
+///
+/// LevelDB iterators only know where to start, so the range ends at the first
+/// key outside of it. That one key is read, nothing after it is.
+struct Range<I> {
+  inner: I,
+  keytype: KeyType,
+  to: Option<Id>,
+  visited: u64,
+  done: bool
+}
+
+impl<I> Range<I> {
+  fn new(inner: I, keytype: KeyType, to: Option<Id>) -> Range<I> {
+    Range { inner, keytype, to, visited: 0, done: false }
+  }
+}
+
+impl<I> Iterator for Range<I> where I: Iterator, I::Item: RangeItem {
+  type Item = I::Item;
+
+  fn next(&mut self) -> Option<I::Item> {
+    if self.done {
+      return None
+    }
+    match self.inner.next() {
+      Some(item) => {
+        self.visited += 1;
+        let in_range = {
+          let key = item.key();
+          key.keytype == self.keytype && self.to.is_none_or(|to| key.id < to)
+        };
+        if in_range {
+          Some(item)
+        } else {
+          self.done = true;
+          None
+        }
+      },
+      None => {
+        self.done = true;
+        None
+      }
+    }
+  }
+}
+
+/// Decodes entries read from a LevelDB snapshot it owns, reading their
+/// offload markers from the same snapshot.
+struct SnapshotEntries<'a, I> {
+  // borrows `snapshot`, so it has to be dropped first
+  entries: I,
+  snapshot: Box<Snapshot<'a, Key>>,
+  journal: &'a Journal
+}
+
+impl<'a, I> SnapshotEntries<'a, I> where I: Iterator<Item = (Key, Vec<u8>)> {
+  fn new<F>(journal: &'a Journal, entries: F) -> SnapshotEntries<'a, I> where F: FnOnce(ReadOptions<'a, Key>) -> I {
+    let snapshot = Box::new(journal.db.snapshot());
+    // the box keeps the snapshot in place for as long as `entries` lives,
+    // which the field order drops before it
+    let pinned = unsafe { &*(&*snapshot as *const Snapshot<'a, Key>) };
+    let mut read_options = ReadOptions::new();
+    read_options.snapshot = Some(pinned);
+    SnapshotEntries { entries: entries(read_options), snapshot, journal }
+  }
+}
+
+impl<'a, I> Iterator for SnapshotEntries<'a, I> where I: Iterator<Item = (Key, Vec<u8>)> {
+  type Item = Result<(Id, Vec<u8>), Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let (key, stored) = self.entries.next()?;
+    Some(self.journal.decode_entry_in(Some(&self.snapshot), key.id, stored))
+  }
+}
+
+/// Delivery bookkeeping for an entry that has been handed out by `reserve`.
+///
+/// Stored under `KeyType::Delivery` with the same id as the entry itself.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+struct Delivery {
+  attempts: u32,
+  deadline: u64 // milliseconds since the epoch, 0 if the entry is not in flight
+}
+
+/// What `Journal::iter_reserved` reports about an entry in flight.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct Reservation {
+  /// How many times the entry has been reserved, this time included.
+  pub attempts: u32,
+  /// When `redeliver_expired` hands the entry out again.
+  pub deadline: SystemTime
+}
+
+impl Delivery {
+  fn encode(&self) -> [u8; 12] {
+    let mut result = [0u8; 12];
+    result[0..4].copy_from_slice(&self.attempts.to_be_bytes());
+    result[4..12].copy_from_slice(&self.deadline.to_be_bytes());
+    result
+  }
+
+  fn decode(data: &[u8]) -> Option<Delivery> {
+    if data.len() != 12 {
+      return None
+    }
+    let mut attempts = [0u8; 4];
+    let mut deadline = [0u8; 8];
+    attempts.copy_from_slice(&data[0..4]);
+    deadline.copy_from_slice(&data[4..12]);
+    Some(Delivery { attempts: u32::from_be_bytes(attempts), deadline: u64::from_be_bytes(deadline) })
+  }
+}
+
+fn encode_u64(value: u64) -> [u8; 8] {
+  value.to_be_bytes()
+}
+
+fn decode_u64(data: &[u8]) -> Option<u64> {
+  if data.len() != 8 {
+    return None
+  }
+  let mut bytes = [0u8; 8];
+  bytes.copy_from_slice(data);
+  Some(u64::from_be_bytes(bytes))
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// The kind of operation an `Event` reports.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Operation {
+  Push,
+  Pop,
+  Reserve,
+  Ack,
+  Nack,
+  Error
+}
+
+/// Passed to the observer installed with `Journal::set_observer`.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Event {
+  pub operation: Operation,
+  pub id: Id,
+  /// The size of the value involved in bytes, 0 if there is none.
+  pub size: usize,
+  /// `JournalOptions::name` of the journal, so one observer can tell
+  /// several journals apart.
+  pub journal: Option<Arc<str>>
+}
+
+/// Sent to the receivers returned by `Journal::subscribe_events` for every
+/// push and pop, with the cursors right after it.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct CursorEvent {
+  /// `Operation::Push` or `Operation::Pop`.
+  pub operation: Operation,
+  /// The id the next push will get.
+  pub head: Id,
+  /// The id of the next entry to pop.
+  pub tail: Id
+}
+
+/// What the handler installed with `Journal::set_error_handler` wants done
+/// about an error.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum ErrorAction {
+  /// Try the failed write again.
+  Retry,
+  /// Report the error the way the journal does without a handler.
+  Fail,
+  /// Panic.
+  Abort
+}
+
+type ErrorHandler = Box<dyn Fn(&Error) -> ErrorAction + Send + Sync>;
+
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+  match fs::rename(from, to) {
+    Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => {
+      copy_dir(from, to)?;
+      fs::remove_dir_all(from)
+    },
+    result => result
+  }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+  fs::create_dir(to)?;
+  for entry in fs::read_dir(from)? {
+    let entry = entry?;
+    let target = to.join(entry.file_name());
+    if entry.file_type()?.is_dir() {
+      copy_dir(&entry.path(), &target)?;
+    } else {
+      fs::copy(entry.path(), &target)?;
+    }
+  }
+  fs::File::open(to)?.sync_all()
+}
+
+struct DeadLetter {
+  journal: Box<Journal>,
+  max_attempts: u32
+}
+
+/// A persistent queue on top of LevelDB.
+///
+/// Failed reads and writes and malformed data are returned as `Error`s.
 pub struct Journal {
   db: Database<Key>,
+  path: PathBuf,
+  options: JournalOptions,
+  name: Option<Arc<str>>,
   head: Key, // The key that points to the last value written
   tail: Key, // The key that points to the earliest value written, but not read
-  reserved_tail: Key // The key that points to the beginning of the reserved block
+  reserved_tail: Key, // The key that points to the beginning of the reserved block
+  visibility_timeout: Duration,
+  dead_letter: Option<DeadLetter>,
+  observer: Option<Box<dyn Fn(Event) + Send + Sync>>,
+  error_handler: Option<ErrorHandler>,
+  subscribers: Mutex<Vec<Sender<CursorEvent>>>,
+  auto_compact_after: Option<u64>,
+  consumed: u64, // entries popped or acked since the last compaction
+  durable_head: Id, // entries below this id have been synced to disk
+  format_version: u64,
+  offload: Option<Mutex<fs::File>>,
+  total_pushed: u64,
+  total_popped: u64,
+  sync_supported: bool,
+  cursors_rebuilt: bool,
+  deferred: u64, // entries popped but not deleted yet
+  stored: u64, // entries stored, the deferred ones included
+  in_flight: u64, // entries reserved and not acked or nacked yet
+  reserved_ahead: BTreeSet<Id>, // entries at or after the tail reserved out of order
+  counters: metrics::Counters,
+  #[cfg(test)]
+  failing_writes: AtomicUsize, // number of upcoming writes to fail
+  #[cfg(test)]
+  interrupted_writes: AtomicUsize, // number of upcoming writes to interrupt
+  #[cfg(test)]
+  value_reads: AtomicUsize // number of values read back so far
+}
+
+/// Shows where the journal is and its cursors, never any values.
+impl fmt::Debug for Journal {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Journal")
+      .field("name", &self.name())
+      .field("path", &self.path)
+      .field("head", &self.head.id)
+      .field("tail", &self.tail.id)
+      .field("reserved_tail", &self.reserved_tail.id)
+      .field("len", &self.len())
+      .finish()
+  }
+}
+
+/// Encrypts values before they are written to disk.
+///
+/// The journal picks no algorithm and manages no keys itself; implement this
+/// over the AEAD of your choice, keyed however your application keys things,
+/// and pass it in `JournalOptions::cipher`.
+pub trait ValueCipher: Send + Sync {
+  /// Encrypts `plaintext` under a fresh nonce, returning the nonce and the
+  /// ciphertext. Nonces must never repeat for the same key. They are stored
+  /// next to each entry and must be at most 255 bytes long.
+  fn seal(&self, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>);
+
+  /// Decrypts and authenticates `ciphertext`, None if that fails.
+  fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// What `pop` and `reserve` do with an entry at the tail that can not be
+/// read back, because it fails to decrypt or its offloaded value is gone.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum CorruptEntryPolicy {
+  /// Return the error, like any other. The entry stays and blocks the queue.
+  Error,
+  /// Delete the entry and carry on with the next one. It is reported to the
+  /// observer with `Operation::Error`, and not dead-lettered, as there is no
+  /// value to hand over.
+  SkipAndDelete
+}
+
+/// The limit on the size of a journal, returned by `Journal::bounds`.
+///
+/// There is no limit on the bytes a journal takes up.
+/// `JournalOptions::max_value_size` bounds single values only.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Bounds {
+  Unbounded,
+  /// At most this many entries that have not been acked or popped, from
+  /// `JournalOptions::capacity`.
+  Count(u64)
+}
+
+/// Options to consider when opening a journal.
+#[derive(Clone)]
+pub struct JournalOptions {
+  /// fsync the database directory and its parent after creating a new
+  /// journal, so the journal itself survives a crash right after it was
+  /// created, not just the entries written to it. Only has an effect on
+  /// Unix-like systems.
+  ///
+  /// default: false
+  pub sync_directory: bool,
+  /// Encrypts every value at rest. Each entry is stored as the nonce length
+  /// as one byte, the nonce and the ciphertext. A journal has to be opened
+  /// with the same cipher and key it was written with.
+  ///
+  /// default: None
+  pub cipher: Option<Arc<dyn ValueCipher>>,
+  /// Makes `push` reject values larger than this many bytes with
+  /// `Error::ValueTooLarge`.
+  ///
+  /// default: None, values of any size are accepted
+  pub max_value_size: Option<usize>,
+  /// fsync every write. With this off, writes are only handed to the OS and
+  /// a crash of the machine can lose the most recent ones; `flush` syncs
+  /// them and `is_durable` tells which pushes made it.
+  ///
+  /// default: true
+  pub sync_writes: bool,
+  /// Stores values larger than this many bytes in a separate append-only
+  /// file next to the database, keeping only their offset and length in
+  /// LevelDB, so large values are not rewritten by every compaction.
+  /// Reads are unaffected. The file only grows, space of removed values is
+  /// not reclaimed.
+  ///
+  /// default: None, every value is kept in LevelDB
+  pub offload_threshold: Option<usize>,
+  /// Makes `push` fail with `Error::Full` while this many entries have not
+  /// been acked or popped, reserved ones included.
+  ///
+  /// default: None, unbounded
+  pub capacity: Option<u64>,
+  /// Fails opening with the `Error::Io` of the probe if a test write to the
+  /// journal's directory cannot be synced, instead of only reporting that
+  /// in `Journal::sync_supported`.
+  ///
+  /// default: false
+  pub require_sync: bool,
+  /// What to do about entries that can not be read back.
+  ///
+  /// default: CorruptEntryPolicy::Error
+  pub corrupt_entry_policy: CorruptEntryPolicy,
+  /// Turns on LevelDB's paranoid checks: it verifies the database more
+  /// thoroughly and fails as soon as it finds damage, opening included,
+  /// instead of skipping over what it can not read.
+  ///
+  /// default: false
+  pub paranoid_checks: bool,
+  /// Makes `pop` only move the tail cursor, which is stored instead, and
+  /// leave the entry to be deleted together with this many others in one
+  /// write, or by `flush`. Entries popped while others are reserved are
+  /// deleted right away. Until they are deleted, `get` still finds popped
+  /// entries; reopening the journal deletes them.
+  ///
+  /// default: None, every pop deletes its entry
+  pub deferred_deletes: Option<u64>,
+  /// Makes `reserve` and its variants hand out nothing while this many
+  /// entries are in flight, until acks, nacks or `redeliver_expired` free
+  /// a slot. `reserve_all` reserves only as many as there are free slots.
+  ///
+  /// default: None, unlimited
+  pub max_in_flight: Option<u64>,
+  /// Fails opening with `Error::MissingCursors` if the journal holds
+  /// entries but no stored head cursor, having been written by a version
+  /// that did not store it or by another tool, instead of rebuilding the
+  /// head from the newest entry and storing it, which
+  /// `Journal::cursors_rebuilt` reports.
+  ///
+  /// default: false
+  pub require_cursors: bool,
+  /// Identifies the journal in panic messages, the messages of the
+  /// database and I/O errors it returns, its `Debug` output and the
+  /// events passed to the observer. It is only kept in memory, so it has
+  /// to be given every time the journal is opened.
+  ///
+  /// default: None
+  pub name: Option<String>
+}
+
+impl JournalOptions {
+  /// Create a new `JournalOptions` struct with default settings.
+  pub fn new() -> JournalOptions {
+    JournalOptions {
+      sync_directory: false,
+      cipher: None,
+      max_value_size: None,
+      sync_writes: true,
+      offload_threshold: None,
+      capacity: None,
+      require_sync: false,
+      corrupt_entry_policy: CorruptEntryPolicy::Error,
+      paranoid_checks: false,
+      deferred_deletes: None,
+      max_in_flight: None,
+      require_cursors: false,
+      name: None
+    }
+  }
+}
+
+impl fmt::Debug for JournalOptions {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("JournalOptions")
+      .field("sync_directory", &self.sync_directory)
+      .field("cipher", &self.cipher.is_some())
+      .field("max_value_size", &self.max_value_size)
+      .field("sync_writes", &self.sync_writes)
+      .field("offload_threshold", &self.offload_threshold)
+      .field("capacity", &self.capacity)
+      .field("require_sync", &self.require_sync)
+      .field("corrupt_entry_policy", &self.corrupt_entry_policy)
+      .field("paranoid_checks", &self.paranoid_checks)
+      .field("deferred_deletes", &self.deferred_deletes)
+      .field("max_in_flight", &self.max_in_flight)
+      .field("require_cursors", &self.require_cursors)
+      .field("name", &self.name)
+      .finish()
+  }
+}
+
+impl Default for JournalOptions {
+  fn default() -> JournalOptions {
+    JournalOptions::new()
+  }
+}
+
+/// Whether reading an entry failed on the entry itself rather than on
+/// reading it.
+fn is_corrupt(err: &Error) -> bool {
+  match *err {
+    Error::Decryption(_) => true,
+    Error::Io(ref err) => err.kind() == io::ErrorKind::InvalidData,
+    _ => false
+  }
+}
+
+/// Whether LevelDB failed because a system call was interrupted by a
+/// signal. Its env reports errno as text, this is `strerror(EINTR)`.
+fn is_interrupted(err: &DbError) -> bool {
+  err.to_string().contains("Interrupted system call")
+}
+
+/// LevelDB takes paths as C strings of UTF-8 and fails on anything else
+/// in unhelpful ways, so paths are checked before they get there.
+fn validate_path(path: &Path) -> Result<(), Error> {
+  let invalid = |reason| Err(Error::InvalidPath(path.to_path_buf(), reason));
+  let name = match path.to_str() {
+    Some(name) => name,
+    None => return invalid("path is not valid UTF-8")
+  };
+  if name.is_empty() {
+    return invalid("path is empty")
+  }
+  if name.contains('\0') {
+    return invalid("path contains a NUL byte")
+  }
+  // without the \\?\ prefix Windows refuses paths longer than MAX_PATH
+  if cfg!(windows) && name.len() >= 260 && !name.starts_with("\\\\?\\") {
+    return invalid("path is longer than 259 characters, use an absolute path prefixed with \\\\?\\")
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+  fs::File::open(path)?.sync_all()?;
+  match path.parent() {
+    Some(parent) if parent != Path::new("") => fs::File::open(parent)?.sync_all(),
+    _ => fs::File::open(".")?.sync_all()
+  }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+  Ok(())
 }
 
 impl Journal {
-  fn new(path: &Path) -> Result<Journal, Error> {
+  /// The LevelDB options a journal is opened with, always over the default
+  /// filesystem env.
+  fn db_options(journal_options: &JournalOptions) -> Options {
     let mut options = Options::new();
+    options.paranoid_checks = journal_options.paranoid_checks;
+    options
+  }
+
+  fn new(path: &Path, journal_options: JournalOptions) -> Result<Journal, Error> {
+    let mut options = Journal::db_options(&journal_options);
     options.create_if_missing = true;
-    let db = Database::open_with_comparator(path, options, OrdComparator::new("journal-comparator".into()));
+    options.error_if_exists = true;
+    let db = Database::open_with_comparator(path, options, journal_comparator());
     let head = Key { keytype: KeyType::Queue, id: 0 };
     let tail = Key { keytype: KeyType::Queue, id: 0 };
     let reserved_tail = Key { keytype: KeyType::Queue, id: 0 };
     match db {
-      Ok(new) => Ok(Journal { db: new, head: head, tail: tail, reserved_tail: reserved_tail }),
-      Err(e) => Err(e)
+      Ok(new) => {
+        let mut journal = Journal::with_keys(new, path, journal_options, head, tail, reserved_tail);
+        journal.offload = Journal::open_offload(path, &journal.options)?;
+        journal.probe_sync()?;
+        let mut batch = Writebatch::new();
+        batch.put(Key::new(KeyType::Meta, FORMAT_META), &encode_u64(FORMAT_VERSION));
+        journal.count_stored(&mut batch, 0);
+        journal.write(&batch)?;
+        if journal.options.sync_directory {
+          sync_dir(path)?;
+        }
+        Ok(journal)
+      },
+      Err(e) => Err(Error::from_open(path, e))
     }
   }
 
-  fn open_existing(path: &Path) -> Result<Journal,Error> {
-    let mut options = Options::new();
+  fn open_existing(path: &Path, journal_options: JournalOptions) -> Result<Journal,Error> {
+    let mut options = Journal::db_options(&journal_options);
     options.create_if_missing = false;
-    let db = Database::open_with_comparator(path, options, OrdComparator::new("journal-comparator".into()));
+    let db = Database::open_with_comparator(path, options, journal_comparator());
     match db {
-      Ok(mut existing) => {
-        let (head, tail, reserved_tail) = Journal::read_keys(&mut existing);
-        Ok(Journal { db: existing, head: head, tail: tail, reserved_tail: reserved_tail })
+      Ok(existing) => {
+        let format_version = Journal::read_cursor(&existing, FORMAT_META)?.unwrap_or(1);
+        if format_version > FORMAT_VERSION {
+          return Err(Error::UnsupportedFormat(format_version))
+        }
+        let rebuild = Journal::read_cursor(&existing, HEAD_META)?.is_none()
+          && Journal::last_key_of(&existing, KeyType::Queue).is_some();
+        if rebuild && journal_options.require_cursors {
+          return Err(Error::MissingCursors(path.to_path_buf()))
+        }
+        let (head, tail, reserved_tail) = Journal::read_keys(&existing)?;
+        let mut journal = Journal::with_keys(existing, path, journal_options, head, tail, reserved_tail);
+        if rebuild {
+          let mut batch = Writebatch::new();
+          batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(head.id));
+          journal.write(&batch)?;
+          journal.cursors_rebuilt = true;
+        }
+        journal.format_version = format_version;
+        journal.total_pushed = Journal::read_cursor(&journal.db, PUSHED_META)?.unwrap_or(0);
+        journal.total_popped = Journal::read_cursor(&journal.db, POPPED_META)?.unwrap_or(0);
+        journal.stored = match Journal::read_cursor(&journal.db, STORED_META)? {
+          Some(stored) => stored,
+          None => {
+            // journals written before the count existed are counted once
+            let stored = Journal::keys_in(&journal.db, KeyType::Queue, 0, None).count() as u64;
+            let mut batch = Writebatch::new();
+            journal.count_stored(&mut batch, stored);
+            journal.write(&batch)?;
+            stored
+          }
+        };
+        journal.offload = Journal::open_offload(path, &journal.options)?;
+        journal.probe_sync()?;
+        if Journal::read_cursor(&journal.db, TAIL_META)?.is_some() {
+          journal.delete_deferred()?;
+        }
+        // an interrupted defragment leaves a gap among the entries, close it
+        // before anything pops up to it
+        if Journal::read_cursor(&journal.db, DEFRAGMENT_META)?.is_some() {
+          journal.defragment()?;
+        }
+        Ok(journal)
       },
-      Err(e) => Err(e)
+      Err(e) => Err(Error::from_open(path, e))
     }
   }
 
-  fn read_keys<'a>(db: &'a Database<Key>) -> (Key, Key, Key) {
-    let read_options = ReadOptions::new();
-    let mut iter = db.keys_iter(read_options);
-    let reserved_tail = Key { keytype: KeyType::Queue, id: 0 };
-    if let Some(first) = iter.next() {
-      let tail = first;
-      if let Some(_) = iter.next() {
-        let last = iter.last().unwrap();
-        let head = last;
-        (head.clone(), tail.clone(), reserved_tail)
-      } else {
-        (tail.clone(), tail.clone(), reserved_tail)
-      }
-    } else {
-      // we have a db, but no keys in it
-      let queue_head = Key { keytype: KeyType::Queue, id: 0 };
-      let queue_tail = Key { keytype: KeyType::Queue, id: 0 };
-      (queue_head, queue_tail, reserved_tail)
+  fn with_keys(db: Database<Key>, path: &Path, options: JournalOptions, head: Key, tail: Key, reserved_tail: Key) -> Journal {
+    Journal {
+      db,
+      path: path.to_path_buf(),
+      name: options.name.as_ref().map(|name| Arc::from(name.as_str())),
+      options,
+      head,
+      tail,
+      reserved_tail,
+      visibility_timeout: Duration::from_secs(30),
+      dead_letter: None,
+      observer: None,
+      error_handler: None,
+      subscribers: Mutex::new(Vec::new()),
+      auto_compact_after: None,
+      consumed: 0,
+      durable_head: head.id,
+      format_version: FORMAT_VERSION,
+      offload: None,
+      total_pushed: 0,
+      total_popped: 0,
+      sync_supported: true,
+      cursors_rebuilt: false,
+      deferred: 0,
+      stored: 0,
+      in_flight: 0,
+      reserved_ahead: BTreeSet::new(),
+      counters: metrics::Counters::default(),
+      #[cfg(test)]
+      failing_writes: AtomicUsize::new(0),
+      #[cfg(test)]
+      interrupted_writes: AtomicUsize::new(0),
+      #[cfg(test)]
+      value_reads: AtomicUsize::new(0)
     }
   }
 
-  pub fn open(path: &Path) -> Result<Journal,Error> {
-    let res = Journal::open_existing(path);
-    match res {
-      Ok(j) => Ok(j),
-      Err(_) => {
-        Journal::new(path)
+  fn probe_sync(&mut self) -> Result<(), Error> {
+    let probe = self.path.join(SYNC_PROBE_FILE);
+    let result = fs::File::create(&probe).and_then(|mut file| {
+      file.write_all(b"probe")?;
+      file.sync_all()
+    });
+    let _ = fs::remove_file(&probe);
+    match result {
+      Err(err) if self.options.require_sync => Err(Error::Io(err)),
+      result => {
+        self.sync_supported = result.is_ok();
+        Ok(())
       }
     }
   }
 
-  pub fn push(&mut self, data: &[u8]) {
-    let mut write_options = WriteOptions::new();
-    write_options.sync = true;
-    self.db.put(write_options, self.head, data).unwrap_or_else(|err| {
-      panic!("error writing to journal: {:?}", err)
-    });
-
-    self.head.id = self.head.id + 1;
+  // opened when offloading is on, or when it was on once and left values
+  fn open_offload(path: &Path, options: &JournalOptions) -> Result<Option<Mutex<fs::File>>, Error> {
+    let file = path.join(OFFLOAD_FILE);
+    if options.offload_threshold.is_none() && !file.exists() {
+      return Ok(None)
+    }
+    let file = fs::OpenOptions::new().read(true).append(true).create(true).open(file)?;
+    Ok(Some(Mutex::new(file)))
   }
 
-  pub fn pop(&mut self) -> Option<Vec<u8>> {
-    if self.head.id >= self.tail.id {
-      let res = self.peek();
-      self.remove(false);
-      if res.is_some() {
-        self.tail.id = self.tail.id + 1;
+  fn read_keys(db: &Database<Key>) -> Result<(Key, Key, Key), DbError> {
+    let stored_head = Journal::read_cursor(db, HEAD_META)?;
+    let stored_tail = Journal::read_cursor(db, TAIL_META)?;
+    // entries below the stored tail were popped, but not deleted yet
+    let first = Journal::keys_in(db, KeyType::Queue, stored_tail.unwrap_or(0), None).next();
+    let last = Journal::last_key_of(db, KeyType::Queue);
+    let keys = match (first, last) {
+      (Some(tail), Some(last)) => {
+        // the stored head is missing in journals written before it existed
+        let head = Key::new(KeyType::Queue, stored_head.unwrap_or(0).max(last.id + 1));
+        // anything that was reserved but not acked is handed out again
+        let reserved_tail = tail;
+        (head, tail, reserved_tail)
+      },
+      _ => {
+        // we have a db, but no entries in it
+        let queue_head = Key { keytype: KeyType::Queue, id: stored_head.unwrap_or(0) };
+        let queue_tail = queue_head;
+        let reserved_tail = queue_head;
+        (queue_head, queue_tail, reserved_tail)
       }
-      return res;
-    } else {
-      None
-    }
+    };
+    Ok(keys)
   }
 
-  pub fn peek(&self) -> Option<Vec<u8>> {
-    if self.head.id >= self.tail.id {
-      let read_options = ReadOptions::new();
-      let result = self.db.get(read_options, self.tail).unwrap_or_else(|err| {
-        panic!("error reading from journal: {:?}", err)
-      });
-      result
+  fn read_cursor(db: &Database<Key>, slot: Id) -> Result<Option<Id>, DbError> {
+    let read_options = ReadOptions::new();
+    let result = db.get(read_options, Key::new(KeyType::Meta, slot))?;
+    Ok(result.and_then(|data| decode_u64(&data)))
+  }
+
+  fn keys_in(db: &Database<Key>, keytype: KeyType, from: Id, to: Option<Id>) -> Range<KeyIterator<'_, Key>> {
+    let read_options = ReadOptions::new();
+    let iter = db.keys_iter(read_options);
+    iter.seek(&Key::new(keytype, from));
+    Range::new(iter, keytype, to)
+  }
+
+  fn entries_in(db: &Database<Key>, keytype: KeyType, from: Id, to: Option<Id>) -> Range<DbIterator<'_, Key>> {
+    let read_options = ReadOptions::new();
+    let iter = db.iter(read_options);
+    iter.seek(&Key::new(keytype, from));
+    Range::new(iter, keytype, to)
+  }
+
+  fn last_key_of(db: &Database<Key>, keytype: KeyType) -> Option<Key> {
+    let read_options = ReadOptions::new();
+    let bound = Key::new(keytype, Id::MAX);
+    let mut iter = db.keys_iter(read_options).reverse();
+    iter.seek(&bound);
+    let past_range = iter.valid();
+    if !past_range {
+      // nothing sorts after the range, so it ends at the last key
+      iter.seek_to_last();
+    }
+    // the first advance only marks the iterator as started
+    if !iter.advance() {
+      return None
+    }
+    if past_range && iter.key() != bound && !iter.advance() {
+      return None
+    }
+    let key = iter.key();
+    if key.keytype == keytype {
+      Some(key)
     } else {
       None
     }
   }
 
-  fn remove(&mut self, reserved: bool) {
-    let key = if reserved {
-                self.tail
-              } else {
-                self.reserved_tail
-              };
+  /// Iterates over the entries that are ready to be popped, oldest first,
+  /// without consuming them.
+  ///
+  /// The iterator sees the journal as it was when `iter` was called: it
+  /// reads the entries and their offload markers from a LevelDB snapshot,
+  /// and the range ends at the head of that time. Entries written or
+  /// removed meanwhile, through a `SharedJournal` or by `Cursor`s on other
+  /// threads, neither appear nor vanish mid-iteration.
+  ///
+  /// An entry that can not be read back yields an error and iteration
+  /// carries on with the next one.
+  pub fn iter(&self) -> impl Iterator<Item = Result<(Id, Vec<u8>), Error>> + '_ {
+    SnapshotEntries::new(self, |read_options| self.ready_in(read_options))
+  }
 
-    let mut write_options = WriteOptions::new();
-    write_options.sync = true;
-    self.db.delete(write_options, key).unwrap_or_else(|err| {
-      panic!("error reading from journal: {:?}", err)
-    });
+  /// Like `iter`, but newest first, walking back from the head to the tail.
+  pub fn iter_rev(&self) -> impl Iterator<Item = Result<(Id, Vec<u8>), Error>> + '_ {
+    SnapshotEntries::new(self, |read_options| self.ready_rev_in(read_options))
+  }
 
-    if reserved {
-      self.advance_to_next_reserved();
+  fn decode_entry(&self, id: Id, stored: Vec<u8>) -> Result<(Id, Vec<u8>), Error> {
+    self.decode_entry_in(None, id, stored)
+  }
+
+  fn decode_entry_in(&self, snapshot: Option<&Snapshot<'_, Key>>, id: Id, stored: Vec<u8>) -> Result<(Id, Vec<u8>), Error> {
+    match self.decode_value_in(snapshot, id, stored) {
+      Ok(data) => Ok((id, data)),
+      Err(err) => Err(self.read_error(id, err))
     }
   }
 
-  fn advance_to_next_reserved(&mut self) {
-    let read_options = ReadOptions::new();
-    let database: &Iterable<Key> = &self.db;
-    let mut iter = database.keys_iter(read_options);
+  // the entries ready to be popped, oldest first; there may be holes
+  // between them where pinned entries were trimmed around
+  fn ready(&self) -> impl Iterator<Item = (Key, Vec<u8>)> + '_ {
+    self.ready_in(ReadOptions::new())
+  }
 
-    if let Some(next_key) = iter.next() {
-      self.reserved_tail = next_key.clone();
-    }
+  fn ready_in<'a>(&'a self, read_options: ReadOptions<'a, Key>) -> impl Iterator<Item = (Key, Vec<u8>)> + 'a {
+    let iter = self.db.iter(read_options);
+    iter.seek(&Key::new(KeyType::Queue, self.tail.id));
+    Range::new(iter, KeyType::Queue, Some(self.head.id))
+      .filter(move |(key, _)| !self.reserved_ahead.contains(&key.id))
   }
 
-  pub fn len(&self) -> u64 {
-    self.head.id - self.tail.id
+  fn first_ready_id(&self) -> Option<Id> {
+    Journal::keys_in(&self.db, KeyType::Queue, self.tail.id, Some(self.head.id))
+      .find(|key| !self.reserved_ahead.contains(&key.id))
+      .map(|key| key.id)
   }
-}
 
-#[cfg(test)]
-mod tests {
-  extern crate tempdir;
+  // like `ready`, newest first
+  fn ready_rev(&self) -> impl Iterator<Item = (Key, Vec<u8>)> + '_ {
+    self.ready_rev_in(ReadOptions::new())
+  }
 
-  use super::{Key,KeyType,Journal};
-  use self::tempdir::TempDir;
-  use std::cmp::Ordering;
+  fn ready_rev_in<'a>(&'a self, read_options: ReadOptions<'a, Key>) -> impl Iterator<Item = (Key, Vec<u8>)> + 'a {
+    let bound = Key::new(KeyType::Queue, self.head.id);
+    let mut iter = self.db.iter(read_options).reverse();
+    iter.seek(&bound);
+    if iter.valid() {
+      // the first advance only marks the iterator as started, here on the
+      // first key at or after the head, which is not part of the range
+      iter.next();
+    } else {
+      iter.seek_to_last();
+    }
+    let tail = self.tail.id;
+    iter.take_while(move |(key, _)| key.keytype == KeyType::Queue && key.id >= tail)
+      .filter(move |(key, _)| !self.reserved_ahead.contains(&key.id))
+  }
 
-  #[test]
-  fn test_compare() {
-    let key = Key { keytype: KeyType::Queue, id: 123 };
-    let key2 = Key { keytype: KeyType::Chunk, id: 123 };
-    let key3 = Key { keytype: KeyType::Queue, id: 124 };
-    assert_eq!(Ordering::Less, key.cmp(&key2));
-    assert_eq!(Ordering::Greater, key2.cmp(&key));
-    assert_eq!(Ordering::Less, key.cmp(&key3));
-    assert_eq!(Ordering::Greater, key3.cmp(&key));
-    assert_eq!(Ordering::Equal, key.cmp(&key));
+  /// Moves the tail to `id`, over ids that hold no ready entry. Entries
+  /// reserved out of order on the way join the reserved block, the reserved
+  /// tail comes along while that is empty.
+  fn skip_to(&mut self, id: Id) {
+    if self.reserved_tail.id == self.tail.id {
+      self.reserved_tail.id = self.reserved_ahead.range(..id).next().cloned().unwrap_or(id);
+    }
+    self.tail.id = id;
+    self.reserved_ahead = self.reserved_ahead.split_off(&id);
   }
 
-  #[test]
-  fn test_equality() {
-    let key = Key { keytype: KeyType::Queue, id: 0 };
-    let key2 = Key { keytype: KeyType::Queue, id: 0 };
-    assert_eq!(Ordering::Equal, key.cmp(&key2));
+  /// Iterates over the entries currently reserved, oldest first, together
+  /// with their reservation. The counterpart of `iter` for in-flight
+  /// entries, yielding errors the same way.
+  pub fn iter_reserved(&self) -> impl Iterator<Item = Result<(Id, Vec<u8>, Reservation), Error>> + '_ {
+    self.reserved_ids().filter_map(move |id| self.reserved_entry(id).map_err(|err| self.read_error(id, err)).transpose())
   }
 
-  #[test]
-  fn test_push() {
-    let dir = TempDir::new("journal_test").unwrap();
-    let mut journal = Journal::open(dir.path()).unwrap();
-    journal.push(&[1u8]);
-    let res = journal.peek();
-    assert!(res.is_some());
+  fn reserved_entry(&self, id: Id) -> Result<Option<(Id, Vec<u8>, Reservation)>, Error> {
+    let delivery = match self.delivery(id)? {
+      Some(delivery) => delivery,
+      None => return Ok(None)
+    };
+    let data = match self.get(id)? {
+      Some(data) => data,
+      None => return Ok(None)
+    };
+    let reservation = Reservation {
+      attempts: delivery.attempts,
+      deadline: UNIX_EPOCH + Duration::from_millis(delivery.deadline)
+    };
+    Ok(Some((id, data, reservation)))
+  }
+
+  // the ids of the reserved entries, oldest first, the block below the
+  // tail and then those reserved out of order
+  fn reserved_ids(&self) -> impl Iterator<Item = Id> + '_ {
+    Journal::keys_in(&self.db, KeyType::Delivery, self.reserved_tail.id, Some(self.tail.id))
+      .map(|key| key.id)
+      .chain(self.reserved_ahead.iter().cloned())
+  }
+
+  pub fn open(path: &Path) -> Result<Journal,Error> {
+    Journal::open_with_options(path, JournalOptions::new())
+  }
+
+  /// Fails with `Error::Locked` if another handle holds the database.
+  pub fn open_with_options(path: &Path, options: JournalOptions) -> Result<Journal,Error> {
+    validate_path(path)?;
+    let res = Journal::open_existing(path, options.clone());
+    match res {
+      Ok(j) => Ok(j),
+      // only LevelDB failing to open the database means there is none yet,
+      // anything else found a journal that must not be replaced
+      Err(Error::Database(err)) => {
+        match Journal::new(path, options) {
+          // there is a database, opening it failed for another reason
+          Err(Error::Database(ref new_err)) if new_err.to_string().contains("exists (error_if_exists is true)") => Err(Error::Database(err)),
+          result => result
+        }
+      },
+      Err(e) => Err(e)
+    }
+  }
+
+  /// Like `open`, but tries again up to `attempts` times in all while the
+  /// database is locked by another handle, say of a process that is still
+  /// shutting down. Waits `backoff` before the second attempt and twice as
+  /// long before each one after that. Other errors are returned right away.
+  pub fn open_with_retry(path: &Path, attempts: u32, backoff: Duration) -> Result<Journal,Error> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+      match Journal::open(path) {
+        Err(Error::Locked(_)) if attempt < attempts => {
+          thread::sleep(delay);
+          delay = delay.saturating_mul(2);
+          attempt += 1;
+        },
+        result => return result
+      }
+    }
+  }
+
+  /// Like `open_with_options`, but fails instead of creating a new journal
+  /// if there is none at `path`. A missing directory is reported as an
+  /// `Error::Io` of kind `NotFound`, and is not created.
+  pub fn open_strict(path: &Path, options: JournalOptions) -> Result<Journal,Error> {
+    validate_path(path)?;
+    // LevelDB creates the directory before finding the database missing
+    if !path.is_dir() {
+      let message = format!("no journal at {}", path.display());
+      return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, message)))
+    }
+    Journal::open_existing(path, options)
+  }
+
+  /// Whether the filesystem the journal is on managed to sync a test write
+  /// when it was opened. If not, synced writes may not survive a crash;
+  /// some network filesystems accept a sync without doing it, which no
+  /// probe can tell.
+  pub fn sync_supported(&self) -> bool {
+    self.sync_supported
+  }
+
+  /// Whether opening found entries but no stored head cursor, and rebuilt
+  /// it from the newest entry, see `JournalOptions::require_cursors`.
+  pub fn cursors_rebuilt(&self) -> bool {
+    self.cursors_rebuilt
+  }
+
+  /// Checks that the database can be written and read, by writing a record
+  /// of its own, reading it back and deleting it again. Both writes are
+  /// synced, whatever `JournalOptions::sync_writes` says.
+  pub fn health_check(&mut self) -> Result<(), Error> {
+    let probe = Key::new(KeyType::Meta, HEALTH_META);
+    let value = encode_u64(now_millis());
+    let mut batch = Writebatch::new();
+    batch.put(probe, &value);
+    self.write_with(&batch, true)?;
+
+    let read = self.db.get(ReadOptions::new(), probe)?;
+    let mut batch = Writebatch::new();
+    batch.delete(probe);
+    self.write_with(&batch, true)?;
+    if read.as_deref() != Some(&value[..]) {
+      let message = "health check read back something other than it wrote";
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, message)))
+    }
+    Ok(())
+  }
+
+  /// How far the journal can grow before `push` fails with `Error::Full`.
+  pub fn bounds(&self) -> Bounds {
+    match self.options.capacity {
+      Some(capacity) => Bounds::Count(capacity),
+      None => Bounds::Unbounded
+    }
+  }
+
+  /// The on-disk format version the journal is stored in, see
+  /// `FORMAT_VERSION`.
+  pub fn format_version(&self) -> u64 {
+    self.format_version
+  }
+
+  /// Upgrades a journal written in an older format to `FORMAT_VERSION`.
+  /// Does nothing for journals that are current already.
+  pub fn migrate(&mut self) -> Result<(), Error> {
+    if self.format_version == FORMAT_VERSION {
+      return Ok(())
+    }
+    // version 1 journals lack the head cursor, which was recovered from the
+    // last entry on open
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(self.head.id));
+    batch.put(Key::new(KeyType::Meta, FORMAT_META), &encode_u64(FORMAT_VERSION));
+    self.write(&batch)?;
+    self.format_version = FORMAT_VERSION;
+    Ok(())
+  }
+
+  /// The directory the journal's database lives in.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// The name the journal was opened with in `JournalOptions::name`.
+  pub fn name(&self) -> Option<&str> {
+    self.name.as_deref()
+  }
+
+  // how panic and error messages refer to the journal
+  fn describe(&self) -> String {
+    match self.name {
+      Some(ref name) => format!("journal {:?}", name),
+      None => "journal".to_string()
+    }
+  }
+
+  // puts the name in front of the message of database and I/O errors,
+  // keeping the variant and the I/O error kind
+  fn named(&self, err: Error) -> Error {
+    if self.name.is_none() {
+      return err
+    }
+    match err {
+      Error::Database(err) => {
+        let message = err.to_string();
+        let message = message.trim_start_matches("LevelDB error: ");
+        Error::Database(DbError::new(format!("{}: {}", self.describe(), message)))
+      },
+      Error::Io(err) => Error::Io(io::Error::new(err.kind(), format!("{}: {}", self.describe(), err))),
+      err => err
+    }
+  }
+
+  /// Moves the journal's database to `new_path` and reopens it there.
+  ///
+  /// The database is closed first. On the same filesystem the directory is
+  /// renamed atomically, otherwise it is copied and the original deleted
+  /// after the copy completed. Fails if `new_path` already exists.
+  /// Reserved entries are handed out again after the move, just like after
+  /// a restart.
+  pub fn relocate(self, new_path: &Path) -> Result<Journal, Error> {
+    validate_path(new_path)?;
+    if new_path.exists() {
+      let message = format!("relocation target {} already exists", new_path.display());
+      return Err(Error::Io(io::Error::new(io::ErrorKind::AlreadyExists, message)))
+    }
+
+    let Journal { db, path, options, offload, visibility_timeout, dead_letter, observer, error_handler, subscribers, .. } = self;
+    // nothing may hold files in the directory while it moves
+    drop(db);
+    drop(offload);
+
+    move_dir(&path, new_path)?;
+
+    let mut journal = Journal::open_existing(new_path, options)?;
+    journal.visibility_timeout = visibility_timeout;
+    journal.dead_letter = dead_letter;
+    journal.observer = observer;
+    journal.error_handler = error_handler;
+    journal.subscribers = subscribers;
+    Ok(journal)
+  }
+
+  /// Splits the journal in two at `id`. The entries below `id` stay, the
+  /// ones from `id` on move, in order, to a new journal created at
+  /// `second_path` with the same options. There they are numbered from 0
+  /// and ready to be popped, even if they were reserved here.
+  ///
+  /// Each journal is written in a single batch, the new one first, so a
+  /// crash in between leaves the moved entries in both journals, never in
+  /// neither. Fails if `second_path` already exists, before anything is
+  /// written. The moved values are all read into memory.
+  pub fn split_at(mut self, id: Id, second_path: &Path) -> Result<(Journal, Journal), Error> {
+    validate_path(second_path)?;
+    if second_path.exists() {
+      let message = format!("split target {} already exists", second_path.display());
+      return Err(Error::Io(io::Error::new(io::ErrorKind::AlreadyExists, message)))
+    }
+    if self.deferred > 0 {
+      self.delete_deferred()?;
+    }
+
+    let mut moved = Vec::new();
+    let mut values = Vec::new();
+    for (key, stored) in Journal::entries_in(&self.db, KeyType::Queue, id, None) {
+      values.push(self.decode_value(key.id, stored)?);
+      moved.push(key.id);
+    }
+    let mut second = Journal::open_with_options(second_path, self.options.clone())?;
+    second.push_all(&values)?;
+
+    let head = id.min(self.head.id).max(self.tail.id);
+    let mut batch = Writebatch::new();
+    for moved_id in moved.iter() {
+      self.delete_entry(&mut batch, *moved_id)?;
+    }
+    batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(head));
+    self.count_stored(&mut batch, self.stored - moved.len() as u64);
+    self.write(&batch)?;
+
+    self.stored -= moved.len() as u64;
+    self.head.id = head;
+    if self.reserved_tail.id >= id {
+      self.reserved_tail = self.tail;
+    }
+    self.reserved_ahead.split_off(&id);
+    let in_block = Journal::keys_in(&self.db, KeyType::Queue, self.reserved_tail.id, Some(self.tail.id)).count() as u64;
+    self.in_flight = in_block + self.reserved_ahead.len() as u64;
+    Ok((self, second))
+  }
+
+  /// Appends every entry of `other` that has not been popped or acked to
+  /// this journal, in order and under new ids, and empties `other`.
+  /// Returns how many entries moved. Entries reserved in `other` arrive
+  /// ready to be popped.
+  ///
+  /// This journal is written first, in a single batch, then the entries
+  /// are deleted from `other` in another one, so a crash in between leaves
+  /// them in both. The moved values are all read into memory.
+  pub fn merge_from(&mut self, other: &mut Journal) -> Result<u64, Error> {
+    if other.deferred > 0 {
+      other.delete_deferred()?;
+    }
+
+    let mut moved = Vec::new();
+    let mut values = Vec::new();
+    for (key, stored) in Journal::entries_in(&other.db, KeyType::Queue, other.reserved_tail.id, Some(other.head.id)) {
+      values.push(other.decode_value(key.id, stored)?);
+      moved.push(key.id);
+    }
+    self.push_all(&values)?;
+
+    let mut batch = Writebatch::new();
+    for id in moved.iter() {
+      other.delete_entry(&mut batch, *id)?;
+    }
+    other.count_stored(&mut batch, other.stored - moved.len() as u64);
+    other.write(&batch)?;
+    other.stored -= moved.len() as u64;
+    other.tail = other.head;
+    other.reserved_tail = other.head;
+    other.in_flight = 0;
+    other.reserved_ahead.clear();
+    Ok(moved.len() as u64)
+  }
+
+  /// Exchanges the contents of this journal with `other`.
+  ///
+  /// Each database handle moves together with its path, cursors, options,
+  /// name and metrics, so every handle keeps its own directory and lock and
+  /// nothing is closed or renamed. Observer, dead letter and visibility timeout stay
+  /// where they are. The swap is atomic for everyone going through these two
+  /// values; the directories themselves are left alone, so opening a path
+  /// afterwards still finds that path's original contents. Use `relocate`
+  /// to move data on disk.
+  pub fn swap(&mut self, other: &mut Journal) {
+    mem::swap(&mut self.db, &mut other.db);
+    mem::swap(&mut self.path, &mut other.path);
+    mem::swap(&mut self.options, &mut other.options);
+    mem::swap(&mut self.name, &mut other.name);
+    mem::swap(&mut self.head, &mut other.head);
+    mem::swap(&mut self.tail, &mut other.tail);
+    mem::swap(&mut self.reserved_tail, &mut other.reserved_tail);
+    mem::swap(&mut self.durable_head, &mut other.durable_head);
+    mem::swap(&mut self.format_version, &mut other.format_version);
+    mem::swap(&mut self.offload, &mut other.offload);
+    mem::swap(&mut self.total_pushed, &mut other.total_pushed);
+    mem::swap(&mut self.total_popped, &mut other.total_popped);
+    mem::swap(&mut self.sync_supported, &mut other.sync_supported);
+    mem::swap(&mut self.cursors_rebuilt, &mut other.cursors_rebuilt);
+    mem::swap(&mut self.consumed, &mut other.consumed);
+    mem::swap(&mut self.deferred, &mut other.deferred);
+    mem::swap(&mut self.stored, &mut other.stored);
+    mem::swap(&mut self.in_flight, &mut other.in_flight);
+    mem::swap(&mut self.reserved_ahead, &mut other.reserved_ahead);
+    mem::swap(&mut self.counters, &mut other.counters);
+  }
+
+  /// Sets how long a reserved entry stays in flight before
+  /// `redeliver_expired` hands it out again. Defaults to 30 seconds.
+  pub fn set_visibility_timeout(&mut self, timeout: Duration) {
+    self.visibility_timeout = timeout;
+  }
+
+  /// Routes entries that have been delivered `max_attempts` times to `dest`
+  /// instead of requeueing them on `nack` or `redeliver_expired`.
+  ///
+  /// The entry is pushed to `dest` before it is removed from this journal,
+  /// so a crash in between leaves it in both.
+  pub fn set_dead_letter(&mut self, dest: Journal, max_attempts: u32) {
+    self.dead_letter = Some(DeadLetter { journal: Box::new(dest), max_attempts });
+  }
+
+  pub fn dead_letter(&self) -> Option<&Journal> {
+    self.dead_letter.as_ref().map(|dl| &*dl.journal)
+  }
+
+  /// The id the next `push` will write under.
+  pub fn next_id(&self) -> Id {
+    self.head.id
+  }
+
+  /// Appends `data` at the head and returns the id it was written under.
+  ///
+  /// Fails with `Error::IdSpaceExhausted` once the head reached the end
+  /// of the id space, see `compact_ids`, with `Error::ValueTooLarge`
+  /// if `data` exceeds `JournalOptions::max_value_size` and with
+  /// `Error::Full` if the journal is at `JournalOptions::capacity`.
+  pub fn push(&mut self, data: &[u8]) -> Result<Id, Error> {
+    self.push_indexed(None, false, data)
+  }
+
+  /// Like `push`, but pins the entry in the same write, see `pin`.
+  pub fn push_pinned(&mut self, data: &[u8]) -> Result<Id, Error> {
+    self.push_indexed(None, true, data)
+  }
+
+  /// Protects the entry `id` from `trim_before`, and so from `commit` and
+  /// `prune_older_than`, which leave it under its id. Popping or acking it
+  /// still removes it. Returns false if there is no such entry.
+  pub fn pin(&mut self, id: Id) -> Result<bool, Error> {
+    let read_options = ReadOptions::new();
+    if self.db.get(read_options, Key::new(KeyType::Queue, id))?.is_none() {
+      return Ok(false)
+    }
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Pinned, id), &[]);
+    self.write(&batch)?;
+    Ok(true)
+  }
+
+  /// Lifts the protection `pin` put on the entry `id`. Returns false if it
+  /// was not pinned.
+  pub fn unpin(&mut self, id: Id) -> Result<bool, Error> {
+    if !self.is_pinned(id)? {
+      return Ok(false)
+    }
+    let mut batch = Writebatch::new();
+    batch.delete(Key::new(KeyType::Pinned, id));
+    self.write(&batch)?;
+    Ok(true)
+  }
+
+  fn is_pinned(&self, id: Id) -> Result<bool, DbError> {
+    let read_options = ReadOptions::new();
+    Ok(self.db.get(read_options, Key::new(KeyType::Pinned, id))?.is_some())
+  }
+
+  /// Pushes the concatenation of `parts` as a single entry. LevelDB only
+  /// takes values in one piece, so the parts are copied together once,
+  /// into a buffer of exactly the right size.
+  pub fn push_vectored(&mut self, parts: &[&[u8]]) -> Result<Id, Error> {
+    let mut data = Vec::with_capacity(parts.iter().map(|part| part.len()).sum());
+    for part in parts {
+      data.extend_from_slice(part);
+    }
+    self.push(&data)
+  }
+
+  /// Like `push`, but also indexes the entry under `user_key` so it can be
+  /// looked up with `find_by_key`.
+  ///
+  /// User keys are meant to be unique. Pushing a key again points it at the
+  /// new entry and the older one can no longer be found by it. User keys
+  /// are stored as they are, even if the journal has a cipher.
+  pub fn push_with_key(&mut self, user_key: &[u8], data: &[u8]) -> Result<Id, Error> {
+    self.push_indexed(Some(user_key), false, data)
+  }
+
+  /// The entry most recently pushed with `user_key` by `push_with_key`,
+  /// None if it has been removed since.
+  pub fn find_by_key(&self, user_key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let read_options = ReadOptions::new();
+    let id = match self.db.get(read_options, index_key(user_key))?.and_then(|data| decode_u64(&data)) {
+      Some(id) => id,
+      None => return Ok(None)
+    };
+    // a different key with the same hash may have taken over the slot
+    let read_options = ReadOptions::new();
+    if self.db.get(read_options, Key::new(KeyType::IndexKey, id))?.as_deref() != Some(user_key) {
+      return Ok(None)
+    }
+    self.get(id)
+  }
+
+  fn push_indexed(&mut self, user_key: Option<&[u8]>, pinned: bool, data: &[u8]) -> Result<Id, Error> {
+    self.check_push(data, 0)?;
+    let next = match self.head.id.checked_add(1) {
+      Some(next) => next,
+      None => return Err(Error::IdSpaceExhausted)
+    };
+
+    // the value and the head cursor land together or not at all
+    let mut batch = Writebatch::new();
+    self.put_entry(&mut batch, self.head.id, user_key, data)?;
+    if pinned {
+      batch.put(Key::new(KeyType::Pinned, self.head.id), &[]);
+    }
+    batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(next));
+    batch.put(Key::new(KeyType::Meta, PUSHED_META), &encode_u64(self.total_pushed + 1));
+    self.count_stored(&mut batch, self.stored + 1);
+    if let Err(err) = self.write(&batch) {
+      self.notify(Operation::Error, self.head.id, 0);
+      return Err(err)
+    }
+
+    let id = self.head.id;
+    self.notify(Operation::Push, id, data.len());
+    self.head.id = next;
+    self.total_pushed += 1;
+    self.stored += 1;
+    if self.options.sync_writes {
+      self.durable_head = next;
+    }
+    Ok(id)
+  }
+
+  /// Pushes all of `entries` in a single write, all or none of them.
+  fn push_all(&mut self, entries: &[Vec<u8>]) -> Result<(), Error> {
+    let next = match self.head.id.checked_add(entries.len() as u64) {
+      Some(next) => next,
+      None => return Err(Error::IdSpaceExhausted)
+    };
+    let entries: Vec<(Id, &[u8])> = (self.head.id..next).zip(entries.iter().map(|data| &data[..])).collect();
+    self.push_at(&entries)
+  }
+
+  /// Like `push_all`, but writes every entry under the id it comes with.
+  /// The ids have to ascend from the head on, the head moves past the last.
+  fn push_at(&mut self, entries: &[(Id, &[u8])]) -> Result<(), Error> {
+    for (pending, &(_, data)) in entries.iter().enumerate() {
+      self.check_push(data, pending as u64)?;
+    }
+    let next = match entries.last() {
+      Some(&(id, _)) => id.checked_add(1).ok_or(Error::IdSpaceExhausted)?,
+      None => return Ok(())
+    };
+
+    let mut batch = Writebatch::new();
+    for &(id, data) in entries.iter() {
+      self.put_entry(&mut batch, id, None, data)?;
+    }
+    batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(next));
+    batch.put(Key::new(KeyType::Meta, PUSHED_META), &encode_u64(self.total_pushed + entries.len() as u64));
+    self.count_stored(&mut batch, self.stored + entries.len() as u64);
+    if let Err(err) = self.write(&batch) {
+      self.notify(Operation::Error, self.head.id, 0);
+      return Err(err)
+    }
+
+    for &(id, data) in entries.iter() {
+      self.notify(Operation::Push, id, data.len());
+    }
+    self.head.id = next;
+    self.total_pushed += entries.len() as u64;
+    self.stored += entries.len() as u64;
+    if self.options.sync_writes {
+      self.durable_head = next;
+    }
+    Ok(())
+  }
+
+  // `pending` entries are about to be pushed along with this one
+  fn check_push(&self, data: &[u8], pending: u64) -> Result<(), Error> {
+    if self.options.max_value_size.is_some_and(|max| data.len() > max) {
+      return Err(Error::ValueTooLarge(data.len()))
+    }
+    // popped entries waiting for `delete_deferred` are no longer held
+    if self.options.capacity.is_some_and(|capacity| self.stored - self.deferred + pending >= capacity) {
+      return Err(Error::Full)
+    }
+    Ok(())
+  }
+
+  /// Adds the writes storing `data` as the entry `id`, without moving the
+  /// head cursor.
+  fn put_entry(&self, batch: &mut Writebatch<Key>, id: Id, user_key: Option<&[u8]>, data: &[u8]) -> Result<(), Error> {
+    self.put_value(batch, id, data)?;
+    batch.put(Key::new(KeyType::Timestamp, id), &encode_u64(now_millis()));
+    if let Some(user_key) = user_key {
+      batch.put(Key::new(KeyType::IndexKey, id), user_key);
+      batch.put(index_key(user_key), &encode_u64(id));
+    }
+    Ok(())
+  }
+
+  /// Adds the write storing `data` as the value of the entry `id`, offloaded
+  /// if it is larger than `JournalOptions::offload_threshold`. Returns
+  /// whether it was.
+  fn put_value(&self, batch: &mut Writebatch<Key>, id: Id, data: &[u8]) -> Result<bool, Error> {
+    let key = Key::new(KeyType::Queue, id);
+    let stored = self.encode_value(data)?;
+    if self.options.offload_threshold.is_some_and(|threshold| stored.len() > threshold) {
+      batch.put(key, &self.offload_value(&stored)?);
+      batch.put(Key::new(KeyType::Offloaded, id), &[]);
+      Ok(true)
+    } else {
+      batch.put(key, &stored);
+      Ok(false)
+    }
+  }
+
+  /// Stores `data` as the value of the stored entry `id` instead of the one
+  /// it holds, keeping its id, its attributes and the cursors.
+  fn replace_value(&self, id: Id, data: &[u8]) -> Result<(), Error> {
+    if self.options.max_value_size.is_some_and(|max| data.len() > max) {
+      return Err(Error::ValueTooLarge(data.len()))
+    }
+    let mut batch = Writebatch::new();
+    if !self.put_value(&mut batch, id, data)? {
+      batch.delete(Key::new(KeyType::Offloaded, id));
+    }
+    self.write(&batch).map_err(|err| self.write_error(id, err))
+  }
+
+  /// Removes and returns the entry at the tail. The tail only moves once
+  /// the entry's removal was written.
+  pub fn pop(&mut self) -> Result<Option<Vec<u8>>, Error> {
+    let data = match self.peek_skipping()? {
+      Some(data) => data,
+      None => return Ok(None)
+    };
+    let defer = self.options.deferred_deletes.is_some() && self.reserved_tail.id == self.tail.id;
+    if defer {
+      self.defer_remove()?;
+    } else {
+      self.remove(self.tail, 1)?;
+    }
+    self.notify(Operation::Pop, self.tail.id, data.len());
+    if self.reserved_tail.id == self.tail.id {
+      self.reserved_tail.id += 1;
+    }
+    self.tail.id += 1;
+    self.consumed(1);
+    if self.options.deferred_deletes.is_some_and(|max| self.deferred >= max) {
+      // the entry is popped either way, a failed delete is tried again by
+      // the next pop
+      if let Err(err) = self.delete_deferred() {
+        self.write_error(self.tail.id, err);
+      }
+    }
+    Ok(Some(data))
+  }
+
+  /// Pops the tail entry into `buf`, replacing its contents, so a consumer
+  /// can keep reusing one allocation. Returns false, leaving `buf` empty,
+  /// if there was nothing to pop.
+  pub fn pop_into(&mut self, buf: &mut Vec<u8>) -> Result<bool, Error> {
+    buf.clear();
+    match self.pop_batch(1)?.pop() {
+      Some(value) => {
+        buf.extend_from_slice(&value);
+        Ok(true)
+      }
+      None => Ok(false)
+    }
+  }
+
+  /// Pops up to `max` entries, oldest first, removing them in a single
+  /// write. Returns fewer if the journal runs out before.
+  ///
+  /// The tail only moves once that write succeeded, so on failure every
+  /// entry is still there to be popped again.
+  pub fn pop_batch(&mut self, max: usize) -> Result<Vec<Vec<u8>>, Error> {
+    let mut popped = Vec::new();
+    let mut batch = Writebatch::new();
+    for (key, stored) in self.ready().take(max) {
+      popped.push((key.id, self.decode_value(key.id, stored)?));
+      self.delete_entry(&mut batch, key.id)?;
+    }
+    let last = match popped.last() {
+      Some(&(id, _)) => id,
+      None => return Ok(Vec::new())
+    };
+    self.count_popped(&mut batch, popped.len() as u64);
+    self.count_stored(&mut batch, self.stored - popped.len() as u64);
+
+    if let Err(err) = self.write(&batch) {
+      self.notify(Operation::Error, self.tail.id, 0);
+      return Err(err)
+    }
+    self.stored -= popped.len() as u64;
+    self.skip_to(last + 1);
+    self.consumed(popped.len() as u64);
+
+    Ok(popped.into_iter().map(|(id, data)| {
+      self.notify(Operation::Pop, id, data.len());
+      data
+    }).collect())
+  }
+
+  /// Runs `f` on the entry at the tail and pops it only if `f` succeeded.
+  ///
+  /// If `f` fails, or the process dies before it returns, the entry stays
+  /// at the tail to be handed out again. Returns None if the journal is
+  /// empty, otherwise what `f` returned. An entry whose removal fails after
+  /// `f` succeeded is handed out again too, so `f` has to cope with seeing
+  /// an entry twice.
+  pub fn pop_transactional<E>(&mut self, f: impl FnOnce(&[u8]) -> Result<(), E>) -> Result<Option<Result<(), E>>, Error> {
+    let (id, data) = match self.ready().next() {
+      Some((key, stored)) => (key.id, self.decode_value(key.id, stored)?),
+      None => return Ok(None)
+    };
+    if let Err(err) = f(&data) {
+      return Ok(Some(Err(err)))
+    }
+
+    let mut batch = Writebatch::new();
+    self.delete_entry(&mut batch, id)?;
+    self.count_popped(&mut batch, 1);
+    self.count_stored(&mut batch, self.stored - 1);
+    if let Err(err) = self.write(&batch) {
+      self.notify(Operation::Error, id, 0);
+      return Err(err)
+    }
+    self.stored -= 1;
+    self.notify(Operation::Pop, id, data.len());
+    self.skip_to(id + 1);
+    self.consumed(1);
+    Ok(Some(Ok(())))
+  }
+
+  /// The `n` oldest and the `n` newest entries ready to be popped, both
+  /// oldest first. Only reads those entries, however long the journal is.
+  ///
+  /// With fewer than `2 * n` entries the two lists overlap, and with fewer
+  /// than `n` both hold every entry.
+  #[allow(clippy::type_complexity)]
+  pub fn peek_ends(&self, n: u64) -> Result<(Vec<Vec<u8>>, Vec<Vec<u8>>), Error> {
+    let decode = |(key, data): (Key, Vec<u8>)| self.decode_value(key.id, data);
+    let oldest = self.ready().take(n as usize).map(decode).collect::<Result<Vec<_>, _>>()?;
+    let mut newest = self.ready_rev().take(n as usize).map(decode).collect::<Result<Vec<_>, _>>()?;
+    newest.reverse();
+    Ok((oldest, newest))
+  }
+
+  /// Up to `count` entries ready to be popped, starting `index` places
+  /// behind the tail, like that many `peek_at` calls but read in a single
+  /// pass over the database. Every value is copied into its own `Vec`, so
+  /// this allocates as much as the values take together.
+  pub fn peek_range(&self, index: u64, count: usize) -> Result<Vec<Vec<u8>>, Error> {
+    self.ready_from(index)
+      .filter(|(key, _)| !self.reserved_ahead.contains(&key.id))
+      .take(count)
+      .map(|(key, data)| self.decode_value(key.id, data))
+      .collect()
+  }
+
+  /// Every entry ready to be popped, oldest first, see `peek_range`.
+  pub fn peek_all(&self) -> Result<Vec<Vec<u8>>, Error> {
+    self.peek_range(0, usize::MAX)
+  }
+
+  fn ready_from(&self, index: u64) -> Range<DbIterator<'_, Key>> {
+    let from = if self.len() == self.head.id - self.tail.id {
+      // without holes every entry sits at its index behind the tail
+      self.tail.id.saturating_add(index).min(self.head.id)
+    } else {
+      let mut keys = Journal::keys_in(&self.db, KeyType::Queue, self.tail.id, Some(self.head.id))
+        .filter(|key| !self.reserved_ahead.contains(&key.id));
+      keys.nth(index as usize).map(|key| key.id).unwrap_or(self.head.id)
+    };
+    Journal::entries_in(&self.db, KeyType::Queue, from, Some(self.head.id))
+  }
+
+  /// A SHA-256 digest over the ids and values of the entries ready to be
+  /// popped, in order. It only depends on those, not on how LevelDB laid
+  /// them out on disk, so replicas holding the same entries agree on it.
+  pub fn digest(&self) -> Result<[u8; 32], Error> {
+    let mut hasher = sha256::Sha256::new();
+    for (key, stored) in self.ready() {
+      let data = self.decode_value(key.id, stored)?;
+      hasher.update(&key.id.to_be_bytes());
+      hasher.update(&(data.len() as u64).to_be_bytes());
+      hasher.update(&data);
+    }
+    Ok(hasher.finish())
+  }
+
+  /// Reads back every entry that has not been popped or acked, reserved
+  /// ones included, and returns the ids of those that can not be decoded,
+  /// because they fail to decrypt or their offloaded value is gone. It
+  /// carries on past them, so one scan finds all of them. Other errors end
+  /// the scan.
+  ///
+  /// LevelDB verifies the checksums of the blocks it reads for the scan.
+  pub fn verify_all(&self) -> Result<Vec<Id>, Error> {
+    let mut read_options = ReadOptions::new();
+    read_options.verify_checksums = true;
+    let iter = self.db.iter(read_options);
+    iter.seek(&Key::new(KeyType::Queue, self.reserved_tail.id));
+    let mut failed = Vec::new();
+    for (key, stored) in Range::new(iter, KeyType::Queue, Some(self.head.id)) {
+      match self.decode_value(key.id, stored) {
+        Ok(_) => (),
+        Err(ref err) if is_corrupt(err) => failed.push(key.id),
+        Err(err) => return Err(err)
+      }
+    }
+    Ok(failed)
+  }
+
+  /// Whether `pop` would return an entry. Unlike `peek` this only looks at
+  /// the counts kept in memory and reads nothing from the database.
+  pub fn has_next(&self) -> bool {
+    !self.is_empty()
+  }
+
+  /// Returns the entry `pop` would return, without removing it.
+  pub fn peek(&self) -> Result<Option<Vec<u8>>, Error> {
+    self.ready().next().map(|(key, data)| self.decode_entry(key.id, data).map(|(_, data)| data)).transpose()
+  }
+
+  /// Like `peek`, but moves the tail to the entry it returns and
+  /// deletes corrupt entries on the way if the
+  /// `JournalOptions::corrupt_entry_policy` says so.
+  fn peek_skipping(&mut self) -> Result<Option<Vec<u8>>, Error> {
+    loop {
+      let next = self.ready().next();
+      let (key, stored) = match next {
+        Some(next) => next,
+        None => {
+          self.skip_to(self.head.id);
+          return Ok(None)
+        }
+      };
+      self.skip_to(key.id);
+      match self.decode_value(self.tail.id, stored) {
+        Ok(data) => return Ok(Some(data)),
+        Err(ref err) if self.options.corrupt_entry_policy == CorruptEntryPolicy::SkipAndDelete && is_corrupt(err) => {
+          self.notify(Operation::Error, self.tail.id, 0);
+          self.remove(self.tail, 0)?;
+          if self.reserved_tail.id == self.tail.id {
+            self.reserved_tail.id += 1;
+          }
+          self.tail.id += 1;
+        },
+        Err(err) => return Err(self.read_error(self.tail.id, err))
+      }
+    }
+  }
+
+  /// Like `peek`, but hands back a `Cow`. LevelDB always gives us a fresh buffer, so for now the value
+  /// is always owned.
+  pub fn peek_cow(&self) -> Result<Option<Cow<'_, [u8]>>, Error> {
+    match self.ready().next() {
+      Some((key, data)) => Ok(Some(Cow::Owned(self.decode_value(key.id, data)?))),
+      None => Ok(None)
+    }
+  }
+
+  /// Looks at the entry `index` places behind the tail without consuming
+  /// it, `peek_at(0)` being the same as `peek`. None if there are not that
+  /// many entries ready.
+  pub fn peek_at(&self, index: u64) -> Result<Option<Vec<u8>>, Error> {
+    self.ready_from(index)
+      .find(|(key, _)| !self.reserved_ahead.contains(&key.id))
+      .map(|(key, data)| self.decode_value(key.id, data))
+      .transpose()
+  }
+
+  /// Looks up the entry with the given absolute id, regardless of where the
+  /// tail currently is. Entries that were popped or acked are gone.
+  pub fn get(&self, id: Id) -> Result<Option<Vec<u8>>, Error> {
+    let read_options = ReadOptions::new();
+    let result = match self.db.get(read_options, Key::new(KeyType::Queue, id)) {
+      Ok(Some(data)) => self.decode_value(id, data).map(Some),
+      Ok(None) => Ok(None),
+      Err(err) => Err(Error::Database(err))
+    };
+    result.map_err(|err| self.named(err))
+  }
+
+  /// Looks up several entries like `get`, returning their values in the
+  /// order of `ids`. Reads them in one pass over the range between the
+  /// lowest and the highest id, so it is cheapest for ids close together.
+  pub fn get_many(&self, ids: &[Id]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+    let mut found = vec![None; ids.len()];
+    let mut order: Vec<usize> = (0..ids.len()).collect();
+    order.sort_by_key(|&i| ids[i]);
+    let (first, last) = match (order.first(), order.last()) {
+      (Some(&first), Some(&last)) => (ids[first], ids[last]),
+      _ => return Ok(found)
+    };
+
+    let mut entries = Journal::entries_in(&self.db, KeyType::Queue, first, last.checked_add(1)).peekable();
+    for i in order {
+      while entries.peek().is_some_and(|(key, _)| key.id < ids[i]) {
+        entries.next();
+      }
+      if let Some((key, stored)) = entries.peek() {
+        if key.id == ids[i] {
+          found[i] = Some(self.decode_value(key.id, stored.clone())?);
+        }
+      }
+    }
+    Ok(found)
+  }
+
+  /// The size in bytes of the value of the entry `id`, None if there is no
+  /// such entry. Looks at what is stored without copying it into a `Vec`,
+  /// and only reads the reference of offloaded values. With a cipher, the
+  /// value has to be decrypted to tell, which costs as much as `get`.
+  pub fn entry_size(&self, id: Id) -> Result<Option<usize>, Error> {
+    if self.options.cipher.is_some() {
+      return Ok(self.get(id)?.map(|data| data.len()))
+    }
+    let read_options = ReadOptions::new();
+    let stored = match self.db.get_bytes(read_options, Key::new(KeyType::Queue, id))? {
+      Some(stored) => stored,
+      None => return Ok(None)
+    };
+    let read_options = ReadOptions::new();
+    if self.offload.is_some() && self.db.get(read_options, Key::new(KeyType::Offloaded, id))?.is_some() {
+      return match stored.get(8..16).and_then(decode_u64) {
+        Some(len) if stored.len() == 16 => Ok(Some(len as usize)),
+        _ => {
+          let message = format!("entry {} has a malformed offload reference", id);
+          Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, message)))
+        }
+      }
+    }
+    Ok(Some(stored.len()))
+  }
+
+  /// The bytes stored for `data`: the value itself, or sealed by the
+  /// cipher. Values are not compressed.
+  fn encode_value<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+    match self.options.cipher {
+      Some(ref cipher) => {
+        let (nonce, ciphertext) = cipher.seal(data);
+        if nonce.len() > u8::MAX as usize {
+          let message = format!("cipher nonce of {} bytes does not fit into 255", nonce.len());
+          return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message)))
+        }
+        let mut stored = Vec::with_capacity(1 + nonce.len() + ciphertext.len());
+        stored.push(nonce.len() as u8);
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+        Ok(Cow::Owned(stored))
+      },
+      None => Ok(Cow::Borrowed(data))
+    }
+  }
+
+  /// Appends `stored` to the offload file, returning the reference to keep
+  /// in its place: offset and length, both 8 bytes big-endian.
+  fn offload_value(&self, stored: &[u8]) -> Result<[u8; 16], Error> {
+    let offload = match self.offload {
+      Some(ref offload) => offload,
+      None => return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "the offload file is not open")))
+    };
+    let mut file = offload.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let offset = file.seek(SeekFrom::End(0))?;
+    file.write_all(stored)?;
+    // the value has to be on disk before the reference to it is
+    if self.options.sync_writes {
+      file.sync_data()?;
+    }
+    let mut reference = [0u8; 16];
+    reference[0..8].copy_from_slice(&offset.to_be_bytes());
+    reference[8..16].copy_from_slice(&(stored.len() as u64).to_be_bytes());
+    Ok(reference)
+  }
+
+  /// Reads the value back that the entry `id` offloaded, if it did.
+  fn load_value(&self, snapshot: Option<&Snapshot<'_, Key>>, id: Id, stored: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let offload = match self.offload {
+      Some(ref offload) => offload,
+      None => return Ok(stored)
+    };
+    let marker_key = Key::new(KeyType::Offloaded, id);
+    let marker = match snapshot {
+      Some(snapshot) => snapshot.get(ReadOptions::new(), marker_key)?,
+      None => self.db.get(ReadOptions::new(), marker_key)?
+    };
+    if marker.is_none() {
+      return Ok(stored)
+    }
+    if stored.len() != 16 {
+      let message = format!("entry {} has a malformed offload reference", id);
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, message)))
+    }
+    let offset = decode_u64(&stored[0..8]).unwrap_or(0);
+    let len = decode_u64(&stored[8..16]).unwrap_or(0);
+    let mut file = offload.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // checked before allocating, a corrupt length must not take the
+    // process down
+    let file_len = file.metadata()?.len();
+    if offset.checked_add(len).is_none_or(|end| end > file_len) {
+      let message = format!("entry {} points past the end of the offload file", id);
+      return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, message)))
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut value = vec![0u8; len as usize];
+    file.read_exact(&mut value)?;
+    Ok(value)
+  }
+
+  fn decode_value(&self, id: Id, stored: Vec<u8>) -> Result<Vec<u8>, Error> {
+    self.decode_value_in(None, id, stored)
+  }
+
+  // reads the offload marker from `snapshot` if there is one, so that it
+  // matches the entry read from there
+  fn decode_value_in(&self, snapshot: Option<&Snapshot<'_, Key>>, id: Id, stored: Vec<u8>) -> Result<Vec<u8>, Error> {
+    #[cfg(test)]
+    self.value_reads.fetch_add(1, AtomicOrdering::SeqCst);
+    let stored = self.load_value(snapshot, id, stored)?;
+    match self.options.cipher {
+      Some(ref cipher) => {
+        let nonce_len = match stored.first() {
+          Some(len) => *len as usize,
+          None => return Err(Error::Decryption(id))
+        };
+        if stored.len() < 1 + nonce_len {
+          return Err(Error::Decryption(id))
+        }
+        let (nonce, ciphertext) = stored[1..].split_at(nonce_len);
+        cipher.open(nonce, ciphertext).ok_or(Error::Decryption(id))
+      },
+      None => Ok(stored)
+    }
+  }
+
+  /// Hands out the entry at the tail without deleting it.
+  ///
+  /// The entry stays in flight until it is acked, nacked or its visibility
+  /// timeout expires.
+  pub fn reserve(&mut self) -> Result<Option<(Id, Vec<u8>)>, Error> {
+    self.reserve_with_timeout(self.visibility_timeout)
+  }
+
+  /// Like `reserve`, but the entry stays in flight for `timeout` instead of
+  /// the journal's visibility timeout. Only this reservation is affected,
+  /// once redelivered the entry gets whatever timeout reserves it next.
+  pub fn reserve_with_timeout(&mut self, timeout: Duration) -> Result<Option<(Id, Vec<u8>)>, Error> {
+    if self.free_slots() == Some(0) {
+      return Ok(None)
+    }
+    let data = match self.peek_skipping()? {
+      Some(data) => data,
+      None => return Ok(None)
+    };
+    let id = self.tail.id;
+    self.reserve_ready(id, data, timeout).map(Some).map_err(|err| self.write_error(id, err))
+  }
+
+  /// Reserves the first ready entry `pred` accepts, like `reserve` would if
+  /// it was at the tail.
+  ///
+  /// Every entry keeps its id. The entries the match skipped stay ready and
+  /// are still the next ones `pop` and `reserve` hand out, in their order,
+  /// while the match is in flight out of order. Should it be handed out
+  /// again, by `nack`, `redeliver_expired` or a restart, it is ready again
+  /// at its id, ahead of them.
+  pub fn reserve_where<F: Fn(&[u8]) -> bool>(&mut self, pred: F) -> Result<Option<(Id, Vec<u8>)>, Error> {
+    if self.free_slots() == Some(0) {
+      return Ok(None)
+    }
+    let mut found = None;
+    for (key, stored) in self.ready() {
+      let data = self.decode_value(key.id, stored)?;
+      if pred(&data) {
+        found = Some((key.id, data));
+        break
+      }
+    }
+    match found {
+      Some((id, data)) => self.reserve_ready(id, data, self.visibility_timeout).map(Some),
+      None => Ok(None)
+    }
+  }
+
+  /// Reserves the ready entry `id` and returns its value, like
+  /// `reserve_where` would if it matched just that entry. None if `id` is
+  /// not ready.
+  pub fn reserve_id(&mut self, id: Id) -> Result<Option<Vec<u8>>, Error> {
+    if id < self.tail.id || id >= self.head.id || self.reserved_ahead.contains(&id) || self.free_slots() == Some(0) {
+      return Ok(None)
+    }
+    let read_options = ReadOptions::new();
+    let stored = match self.db.get(read_options, Key::new(KeyType::Queue, id))? {
+      Some(stored) => stored,
+      None => return Ok(None)
+    };
+    let data = self.decode_value(id, stored)?;
+    let (_, data) = self.reserve_ready(id, data, self.visibility_timeout)?;
+    Ok(Some(data))
+  }
+
+  // reserves the ready entry `id` for `timeout`; the oldest one extends the
+  // reserved block, any other is in flight out of order
+  fn reserve_ready(&mut self, id: Id, data: Vec<u8>, timeout: Duration) -> Result<(Id, Vec<u8>), Error> {
+    let attempts = self.delivery(id)?.map(|d| d.attempts).unwrap_or(0) + 1;
+    let deadline = now_millis().saturating_add(timeout.as_millis() as u64);
+    let delivery = Delivery { attempts, deadline };
+    let oldest = self.first_ready_id() == Some(id);
+
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Delivery, id), &delivery.encode());
+    self.write(&batch)?;
+
+    self.notify(Operation::Reserve, id, data.len());
+    if oldest {
+      self.skip_to(id);
+      self.tail.id += 1;
+    } else {
+      self.reserved_ahead.insert(id);
+    }
+    self.in_flight += 1;
+    Ok((id, data))
+  }
+
+  /// Reserves every ready entry at once, in one write, for processing the
+  /// backlog as a whole. Entries pushed afterwards stay ready.
+  ///
+  /// All of the values are read into memory, so this is meant for journals
+  /// whose backlog is known to be small. Use `pop_batch` or repeated
+  /// `reserve` calls otherwise.
+  pub fn reserve_all(&mut self) -> Result<Vec<(Id, Vec<u8>)>, Error> {
+    let deadline = now_millis().saturating_add(self.visibility_timeout.as_millis() as u64);
+    let slots = self.free_slots();
+    let mut batch = Writebatch::new();
+    let mut reserved = Vec::new();
+    for (key, stored) in self.ready().take(slots.unwrap_or(u64::MAX) as usize) {
+      let data = self.decode_value(key.id, stored)?;
+      let attempts = self.delivery(key.id)?.map(|d| d.attempts).unwrap_or(0) + 1;
+      batch.put(Key::new(KeyType::Delivery, key.id), &Delivery { attempts, deadline }.encode());
+      reserved.push((key.id, data));
+    }
+    if reserved.is_empty() {
+      return Ok(reserved)
+    }
+    self.write(&batch)?;
+
+    for &(id, ref data) in &reserved {
+      self.notify(Operation::Reserve, id, data.len());
+    }
+    self.in_flight += reserved.len() as u64;
+    self.tail.id = match slots {
+      Some(slots) if reserved.len() as u64 == slots => reserved[reserved.len() - 1].0 + 1,
+      _ => self.head.id
+    };
+    // the entries reserved out of order before are part of the block now
+    self.reserved_ahead = self.reserved_ahead.split_off(&self.tail.id);
+    Ok(reserved)
+  }
+
+  /// How many more entries can be reserved under
+  /// `JournalOptions::max_in_flight`, None without a limit.
+  fn free_slots(&self) -> Option<u64> {
+    let max = self.options.max_in_flight?;
+    Some(max.saturating_sub(self.in_flight))
+  }
+
+  /// Reserves the next entry and hands it to `f`, acking it if `f`
+  /// succeeds and nacking it if it fails. Returns what `f` returned, None
+  /// if nothing was ready, and `Err` only for errors of the journal itself.
+  pub fn with_reserved<R, E, F: FnOnce(&[u8]) -> Result<R, E>>(&mut self, f: F) -> Result<Option<Result<R, E>>, Error> {
+    let (id, data) = match self.reserve_where(|_| true)? {
+      Some(reserved) => reserved,
+      None => return Ok(None)
+    };
+    let result = f(&data);
+    if result.is_ok() {
+      self.ack(id)?;
+    } else {
+      self.nack(id)?;
+    }
+    Ok(Some(result))
+  }
+
+  /// Moves the deadline of the reserved entry `id` `by` later, for
+  /// consumers that need longer than they reserved it for. Fails with
+  /// `Error::NotReserved` if the entry is not in flight, which includes
+  /// entries `redeliver_expired` handed out again already.
+  pub fn extend_reservation(&mut self, id: Id, by: Duration) -> Result<(), Error> {
+    let delivery = match self.delivery(id)? {
+      Some(delivery) if self.is_reserved(id)? => delivery,
+      _ => return Err(Error::NotReserved(id))
+    };
+    let deadline = delivery.deadline.saturating_add(by.as_millis() as u64);
+    let delivery = Delivery { attempts: delivery.attempts, deadline };
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Delivery, id), &delivery.encode());
+    self.write(&batch)?;
+    Ok(())
+  }
+
+  /// Removes a reserved entry for good. Returns false if `id` is not in
+  /// flight. The entry stays in flight if its removal fails.
+  pub fn ack(&mut self, id: Id) -> Result<bool, Error> {
+    if !self.is_reserved(id).map_err(|err| self.read_error(id, err))? {
+      return Ok(false)
+    }
+    self.remove(Key::new(KeyType::Queue, id), 1)?;
+    self.in_flight -= 1;
+    self.reserved_ahead.remove(&id);
+    self.notify(Operation::Ack, id, 0);
+    if id == self.reserved_tail.id {
+      self.advance_to_next_reserved();
+    }
+    self.consumed(1);
+    Ok(true)
+  }
+
+  /// Compacts away what acked and popped entries left behind below the
+  /// reserved block.
+  ///
+  /// Deleting an entry only writes a tombstone, which LevelDB drops during
+  /// its next compaction of that range. This forces it for the ranges below
+  /// `reserved_tail`, which no longer hold any live entries.
+  pub fn compact_reserved(&mut self) {
+    let keytypes = [KeyType::Queue].iter().chain(ENTRY_ATTRIBUTES.iter());
+    for keytype in keytypes {
+      let start = Key::new(*keytype, 0);
+      let limit = Key::new(*keytype, self.reserved_tail.id);
+      self.db.compact(&start, &limit);
+    }
+    self.consumed = 0;
+  }
+
+  /// Compacts the whole database, so it takes up little more space on disk
+  /// than the live entries need, and returns how many bytes that freed.
+  /// Meant for after a large drain, it rewrites everything that is left.
+  /// Values in the offload file are not reclaimed.
+  pub fn shrink(&mut self) -> Result<u64, Error> {
+    let before = self.disk_size()?;
+    let start = Key::new(KeyType::Queue, 0);
+    let limit = Key::new(KeyType::Unknown, Id::MAX);
+    self.db.compact(&start, &limit);
+    // LevelDB only rewrites files of the deepest level when a compaction
+    // from above overlaps them, so rewrite the first and the last record
+    // unchanged and compact again to pull everything in between along
+    let read_options = ReadOptions::new();
+    let first = self.db.iter(read_options).next();
+    let read_options = ReadOptions::new();
+    let last = self.db.iter(read_options).last();
+    let mut batch = Writebatch::new();
+    for (key, value) in first.iter().chain(last.iter()) {
+      batch.put(*key, value);
+    }
+    self.write(&batch)?;
+    self.db.compact(&start, &limit);
+    self.consumed = 0;
+    Ok(before.saturating_sub(self.disk_size()?))
+  }
+
+  /// The total size in bytes of the files in the journal's directory.
+  pub fn disk_size(&self) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in fs::read_dir(&self.path)? {
+      let metadata = entry?.metadata()?;
+      if metadata.is_file() {
+        size += metadata.len();
+      }
+    }
+    Ok(size)
+  }
+
+  /// Compacts the entries with ids in `from..to` and their per-entry
+  /// records, making LevelDB drop what removed entries left there. Entries
+  /// still in the range are kept.
+  pub fn compact_range(&mut self, from: Id, to: Id) {
+    if from >= to {
+      return
+    }
+    let keytypes = [KeyType::Queue].iter().chain(ENTRY_ATTRIBUTES.iter());
+    for keytype in keytypes {
+      let start = Key::new(*keytype, from);
+      let limit = Key::new(*keytype, to);
+      self.db.compact(&start, &limit);
+    }
+  }
+
+  /// Runs `compact_reserved` automatically after every `n` entries popped
+  /// or acked, None to turn that off again. Off by default.
+  pub fn set_auto_compact_after(&mut self, n: Option<u64>) {
+    self.auto_compact_after = n;
+  }
+
+  // every write consuming entries counts them with `count_popped` too
+  fn consumed(&mut self, count: u64) {
+    self.consumed += count;
+    self.total_popped += count;
+    if self.auto_compact_after.is_some_and(|n| self.consumed >= n) {
+      self.compact_reserved();
+    }
+  }
+
+  /// Gives up on a reserved entry. It is ready again under its id, ahead of
+  /// the entries pushed after it, or moved to the dead-letter journal once
+  /// it ran out of attempts. Returns false if `id` is not in flight.
+  ///
+  /// The entry stays in flight if it could not be made ready again or
+  /// dead-lettered, and a dead-letter copy written before its removal
+  /// failed is taken out again.
+  pub fn nack(&mut self, id: Id) -> Result<bool, Error> {
+    let delivery = match self.delivery(id).map_err(|err| self.read_error(id, err))? {
+      Some(delivery) if self.is_reserved(id).map_err(|err| self.read_error(id, err))? => delivery,
+      _ => return Ok(false)
+    };
+    let key = Key::new(KeyType::Queue, id);
+    let read_options = ReadOptions::new();
+    let data = match self.db.get(read_options, key) {
+      Ok(data) => data.unwrap_or_default(),
+      Err(err) => return Err(self.read_error(id, err))
+    };
+
+    let exhausted = match self.dead_letter {
+      Some(ref dl) => delivery.attempts >= dl.max_attempts,
+      None => false
+    };
+
+    if exhausted {
+      let (_, plaintext) = self.decode_entry(id, data.clone())?;
+      // `exhausted` is only set with a dead-letter journal
+      let copy = match self.dead_letter.as_mut().map(|dl| dl.journal.push(&plaintext)) {
+        Some(Ok(copy)) => copy,
+        Some(Err(err)) => return Err(self.write_error(id, err)),
+        None => return Ok(false)
+      };
+      if let Err(err) = self.remove(key, 0) {
+        return Err(self.undo_dead_letter(id, copy, err))
+      }
+    } else {
+      let requeued = Delivery { attempts: delivery.attempts, deadline: 0 };
+      let mut batch = Writebatch::new();
+      batch.put(Key::new(KeyType::Delivery, id), &requeued.encode());
+      if let Err(err) = self.write(&batch) {
+        return Err(self.write_error(id, err))
+      }
+      self.return_to_ready(id);
+    }
+    self.in_flight -= 1;
+    self.reserved_ahead.remove(&id);
+    self.notify(Operation::Nack, id, data.len());
+
+    if id == self.reserved_tail.id {
+      self.advance_to_next_reserved();
+    }
+    Ok(true)
+  }
+
+  // makes the entry `id`, which is in flight, ready again under its id
+  fn return_to_ready(&mut self, id: Id) {
+    if self.reserved_ahead.remove(&id) {
+      return
+    }
+    // it is in the reserved block, so the tail moves back to it and the
+    // entries in flight after it are reserved out of order from now on
+    let after: Vec<Id> = Journal::keys_in(&self.db, KeyType::Queue, id + 1, Some(self.tail.id)).map(|key| key.id).collect();
+    self.reserved_ahead.extend(after);
+    self.tail.id = id;
+  }
+
+  // takes the dead-letter copy `copy` of the entry `id` back out after the
+  // entry could not be removed, so that nacking it again does not leave a
+  // second copy
+  fn undo_dead_letter(&mut self, id: Id, copy: Id, err: Error) -> Error {
+    let undone = match self.dead_letter {
+      Some(ref mut dl) => dl.journal.remove(Key::new(KeyType::Queue, copy), 0).is_ok(),
+      None => false
+    };
+    let outcome = if undone {
+      "its dead-letter copy was removed again".to_string()
+    } else {
+      format!("its dead-letter copy is left as entry {}", copy)
+    };
+    let message = format!("entry {} stays in flight, {}: {}", id, outcome, err);
+    Error::Io(io::Error::other(message))
+  }
+
+  /// Nacks every reserved entry whose visibility timeout has passed.
+  /// Returns the number of entries redelivered or dead-lettered. On an
+  /// error, the entries nacked before it stay redelivered.
+  pub fn redeliver_expired(&mut self) -> Result<u64, Error> {
+    let now = now_millis();
+    let reserved: Vec<Id> = self.reserved_ids().collect();
+    let mut count = 0;
+    for id in reserved {
+      let expired = match self.delivery(id) {
+        Ok(delivery) => delivery.is_some_and(|delivery| delivery.deadline <= now),
+        Err(err) => return Err(self.read_error(id, err))
+      };
+      if expired && self.nack(id)? {
+        count += 1;
+      }
+    }
+    Ok(count)
+  }
+
+  /// The number of times the entry `id` has been reserved.
+  pub fn attempts(&self, id: Id) -> Result<u32, Error> {
+    match self.delivery(id) {
+      Ok(delivery) => Ok(delivery.map(|d| d.attempts).unwrap_or(0)),
+      Err(err) => Err(self.read_error(id, err))
+    }
+  }
+
+  fn is_reserved(&self, id: Id) -> Result<bool, DbError> {
+    let in_flight = (id >= self.reserved_tail.id && id < self.tail.id) || self.reserved_ahead.contains(&id);
+    Ok(in_flight && self.delivery(id)?.is_some())
+  }
+
+  fn delivery(&self, id: Id) -> Result<Option<Delivery>, DbError> {
+    let read_options = ReadOptions::new();
+    let result = self.db.get(read_options, Key::new(KeyType::Delivery, id))?;
+    Ok(result.and_then(|data| Delivery::decode(&data)))
+  }
+
+  // `popped` is what the removal adds to `total_popped`
+  fn remove(&mut self, key: Key, popped: u64) -> Result<(), Error> {
+    let mut batch = Writebatch::new();
+    if let Err(err) = self.delete_entry(&mut batch, key.id) {
+      return Err(self.read_error(key.id, err))
+    }
+    if popped > 0 {
+      self.count_popped(&mut batch, popped);
+    }
+    self.count_stored(&mut batch, self.stored - 1);
+
+    if let Err(err) = self.write(&batch) {
+      return Err(self.write_error(key.id, err))
+    }
+    self.stored -= 1;
+    Ok(())
+  }
+
+  /// Pops the tail entry by storing the tail cursor past it, leaving the
+  /// delete to `delete_deferred`. Only valid while nothing is reserved, so
+  /// everything below the reserved block has been popped.
+  fn defer_remove(&mut self) -> Result<(), Error> {
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Meta, TAIL_META), &encode_u64(self.tail.id + 1));
+    self.count_popped(&mut batch, 1);
+    if let Err(err) = self.write(&batch) {
+      return Err(self.write_error(self.tail.id, err))
+    }
+    self.deferred += 1;
+    Ok(())
+  }
+
+  /// Deletes the entries `pop` left behind in one write, along with the
+  /// stored tail cursor, which the first remaining entry replaces.
+  fn delete_deferred(&mut self) -> Result<u64, Error> {
+    let keys: Vec<Key> = Journal::keys_in(&self.db, KeyType::Queue, 0, Some(self.reserved_tail.id)).collect();
+    let mut batch = Writebatch::new();
+    for key in keys.iter() {
+      self.delete_entry(&mut batch, key.id)?;
+    }
+    batch.delete(Key::new(KeyType::Meta, TAIL_META));
+    self.count_stored(&mut batch, self.stored - keys.len() as u64);
+    self.write(&batch)?;
+    self.stored -= keys.len() as u64;
+    self.deferred = 0;
+    Ok(keys.len() as u64)
+  }
+
+  /// Adds the write counting `count` more entries in `total_popped`.
+  fn count_popped(&self, batch: &mut Writebatch<Key>, count: u64) {
+    batch.put(Key::new(KeyType::Meta, POPPED_META), &encode_u64(self.total_popped + count));
+  }
+
+  /// Adds the write recording that `stored` entries are left once the batch
+  /// is written. Every batch adding or deleting entries carries one.
+  fn count_stored(&self, batch: &mut Writebatch<Key>, stored: u64) {
+    batch.put(Key::new(KeyType::Meta, STORED_META), &encode_u64(stored));
+  }
+
+  /// Adds the writes deleting the entry `id`, its per-entry records and the
+  /// index record pointing at it.
+  fn delete_entry(&self, batch: &mut Writebatch<Key>, id: Id) -> Result<(), DbError> {
+    batch.delete(Key::new(KeyType::Queue, id));
+    for keytype in ENTRY_ATTRIBUTES.iter() {
+      batch.delete(Key::new(*keytype, id));
+    }
+    if let Some(index) = self.index_of(id)? {
+      batch.delete(index);
+    }
+    Ok(())
+  }
+
+  /// The index record of the entry `id`, if it was pushed with a user key
+  /// and the index still points at it.
+  fn index_of(&self, id: Id) -> Result<Option<Key>, DbError> {
+    let read_options = ReadOptions::new();
+    let user_key = match self.db.get(read_options, Key::new(KeyType::IndexKey, id))? {
+      Some(user_key) => user_key,
+      None => return Ok(None)
+    };
+    let index = index_key(&user_key);
+    let read_options = ReadOptions::new();
+    let indexed = self.db.get(read_options, index)?.and_then(|data| decode_u64(&data));
+    Ok(if indexed == Some(id) { Some(index) } else { None })
+  }
+
+  /// Adds the writes moving the entry `from` and its per-entry records to `to`.
+  fn move_entry(&self, batch: &mut Writebatch<Key>, from: Id, to: Id, data: &[u8]) -> Result<(), DbError> {
+    let mut attributes = Vec::new();
+    for keytype in ENTRY_ATTRIBUTES.iter() {
+      let read_options = ReadOptions::new();
+      if let Some(value) = self.db.get(read_options, Key::new(*keytype, from))? {
+        attributes.push((*keytype, value));
+      }
+    }
+
+    batch.delete(Key::new(KeyType::Queue, from));
+    for &(keytype, _) in attributes.iter() {
+      batch.delete(Key::new(keytype, from));
+    }
+    batch.put(Key::new(KeyType::Queue, to), data);
+    for &(keytype, ref value) in attributes.iter() {
+      batch.put(Key::new(keytype, to), value);
+    }
+    if let Some(index) = self.index_of(from)? {
+      batch.put(index, &encode_u64(to));
+    }
+    Ok(())
+  }
+
+  /// Every write to the database goes through here, synced unless the
+  /// journal was opened without `JournalOptions::sync_writes`.
+  fn write(&self, batch: &Writebatch<Key>) -> Result<(), Error> {
+    self.write_with(batch, self.options.sync_writes)
+  }
+
+  fn write_with(&self, batch: &Writebatch<Key>, sync: bool) -> Result<(), Error> {
+    loop {
+      let result = match self.write_once(batch, sync) {
+        // a signal arrived during the write, which nothing prevents from
+        // going through on a second try
+        Err(ref err) if is_interrupted(err) => self.write_once(batch, sync),
+        result => result
+      };
+      let err = match result {
+        Ok(()) => return Ok(()),
+        Err(err) => self.named(Error::Database(err))
+      };
+      if self.handle_error(&err) != ErrorAction::Retry {
+        return Err(err)
+      }
+    }
+  }
+
+  fn write_once(&self, batch: &Writebatch<Key>, sync: bool) -> Result<(), DbError> {
+    #[cfg(test)]
+    {
+      if self.failing_writes.load(AtomicOrdering::SeqCst) > 0 {
+        self.failing_writes.fetch_sub(1, AtomicOrdering::SeqCst);
+        return Err(DbError::new("injected write failure".to_string()))
+      }
+      if self.interrupted_writes.load(AtomicOrdering::SeqCst) > 0 {
+        self.interrupted_writes.fetch_sub(1, AtomicOrdering::SeqCst);
+        return Err(DbError::new("IO error: 000003.log: Interrupted system call".to_string()))
+      }
+    }
+
+    let mut write_options = WriteOptions::new();
+    write_options.sync = sync;
+    self.db.write(write_options, batch)
+  }
+
+  /// Syncs every write made so far to disk, offloaded values included, for
+  /// journals opened without `JournalOptions::sync_writes`, and deletes the
+  /// entries popped with `JournalOptions::deferred_deletes`.
+  pub fn flush(&mut self) -> Result<(), Error> {
+    if self.deferred > 0 {
+      self.delete_deferred()?;
+    }
+    // the values have to be on disk before the references to them are
+    if let Some(ref offload) = self.offload {
+      offload.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).sync_data()?;
+    }
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(self.head.id));
+    self.write_with(&batch, true)?;
+    self.durable_head = self.head.id;
+    Ok(())
+  }
+
+  /// Whether the entry pushed under `id` has been synced to disk, so it
+  /// survives a crash. Always true right after the push unless the journal
+  /// was opened without `JournalOptions::sync_writes`.
+  pub fn is_durable(&self, id: Id) -> bool {
+    id < self.durable_head
+  }
+
+  /// Installs a callback that is invoked for every operation on the journal.
+  ///
+  /// Errors are reported with `Operation::Error` right before they are
+  /// returned.
+  pub fn set_observer(&mut self, observer: Box<dyn Fn(Event) + Send + Sync>) {
+    self.observer = Some(observer);
+  }
+
+  /// Returns a receiver getting a `CursorEvent` for every push and pop
+  /// from now on. Any number of them can be subscribed; dropping one
+  /// unsubscribes it.
+  pub fn subscribe_events(&self) -> Receiver<CursorEvent> {
+    let (sender, receiver) = channel();
+    self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(sender);
+    receiver
+  }
+
+  fn notify(&self, operation: Operation, id: Id, size: usize) {
+    self.counters.record(operation, size);
+    if let Some(ref observer) = self.observer {
+      observer(Event { operation, id, size, journal: self.name.clone() });
+    }
+    // pushes and pops are reported before the cursor moves past `id`
+    let event = match operation {
+      Operation::Push => CursorEvent { operation, head: id + 1, tail: self.tail.id },
+      Operation::Pop => CursorEvent { operation, head: self.head.id, tail: id + 1 },
+      _ => return
+    };
+    let mut subscribers = self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    subscribers.retain(|sender| sender.send(event).is_ok());
+  }
+
+  /// Installs a callback that is invoked with every failed write and with
+  /// the failed reads of `peek`, `pop`, `reserve`, `ack`, `nack` and the
+  /// iterators.
+  ///
+  /// Without one, failed writes are returned as they are. The handler can
+  /// log the error and return `ErrorAction::Fail` to keep that behaviour,
+  /// `ErrorAction::Retry` to write the same batch again or
+  /// `ErrorAction::Abort` to panic right away. Reads can not be retried,
+  /// the error is returned after calling it whatever it returns.
+  pub fn set_error_handler(&mut self, handler: ErrorHandler) {
+    self.error_handler = Some(handler);
+  }
+
+  fn handle_error(&self, err: &Error) -> ErrorAction {
+    let action = match self.error_handler {
+      Some(ref handler) => handler(err),
+      None => ErrorAction::Fail
+    };
+    if action == ErrorAction::Abort {
+      panic!("{} aborted on error: {}", self.describe(), err)
+    }
+    action
+  }
+
+  // reports a failed read of the entry `id` and hands the error back
+  fn read_error<E: Into<Error>>(&self, id: Id, err: E) -> Error {
+    let err = self.named(err.into());
+    self.handle_error(&err);
+    self.notify(Operation::Error, id, 0);
+    err
+  }
+
+  // `write` already passed the error to the handler
+  fn write_error<E: Into<Error>>(&self, id: Id, err: E) -> Error {
+    self.notify(Operation::Error, id, 0);
+    err.into()
+  }
+
+  fn advance_to_next_reserved(&mut self) {
+    let next = Journal::keys_in(&self.db, KeyType::Queue, self.reserved_tail.id, Some(self.tail.id)).next();
+    match next {
+      Some(next_key) => self.reserved_tail = next_key,
+      None => self.reserved_tail = self.tail
+    }
+  }
+
+  /// Commits the offset `up_to`, the way consumers tracking a single offset
+  /// acknowledge: every entry below it that is not pinned is deleted in one
+  /// write, ready or reserved. The next `pop` returns the oldest pinned
+  /// entry left below `up_to`, or the entry `up_to` if there is none.
+  ///
+  /// `up_to` is clamped to lie between the tail and the head.
+  pub fn commit(&mut self, up_to: Id) -> Result<(), Error> {
+    let up_to = up_to.max(self.tail.id).min(self.head.id);
+    self.trim_before(up_to)?;
+    Ok(())
+  }
+
+  /// Deletes every entry with an id below `id`, whether it was read or not,
+  /// and returns how many were removed. Popped entries still waiting for
+  /// `JournalOptions::deferred_deletes` are deleted too, but not counted.
+  /// The tail and the reserved block move forward past the removed entries.
+  ///
+  /// Pinned entries are kept under their ids, reserved or ready. The ready
+  /// ones are still the next ones `pop` returns.
+  pub fn trim_before(&mut self, id: Id) -> Result<u64, Error> {
+    // popped entries are deleted first, they are not the caller's to count
+    if self.deferred > 0 {
+      self.delete_deferred()?;
+    }
+    let keys: Vec<Key> = Journal::keys_in(&self.db, KeyType::Queue, 0, Some(id)).collect();
+    let bound = id.min(self.head.id);
+
+    let mut batch = Writebatch::new();
+    let mut ready = 0;
+    let mut reserved = 0;
+    let mut first_kept = None;
+    let mut released = Vec::new();
+    for key in keys.iter() {
+      let ahead = self.reserved_ahead.contains(&key.id);
+      if self.is_pinned(key.id)? {
+        if key.id >= self.tail.id && !ahead && first_kept.is_none() {
+          first_kept = Some(key.id);
+        }
+        continue
+      }
+      self.delete_entry(&mut batch, key.id)?;
+      if ahead {
+        released.push(key.id);
+        reserved += 1;
+      } else if key.id < self.tail.id {
+        reserved += 1;
+      } else {
+        ready += 1;
+      }
+    }
+    self.count_stored(&mut batch, self.stored - ready - reserved);
+    self.write(&batch)?;
+
+    self.stored -= ready + reserved;
+    self.in_flight -= reserved;
+    for id in released {
+      self.reserved_ahead.remove(&id);
+    }
+    if self.tail.id < bound {
+      self.skip_to(first_kept.unwrap_or(bound));
+    }
+    if self.reserved_tail.id < bound {
+      self.advance_to_next_reserved();
+    }
+    Ok(ready + reserved)
+  }
+
+  /// Deletes the entries that were consumed but are still stored, in one
+  /// write, and returns how many there were. Only pops with
+  /// `JournalOptions::deferred_deletes` leave any behind. Stops at the
+  /// reserved block, the entries in flight have not been consumed yet.
+  pub fn purge_consumed(&mut self) -> Result<u64, Error> {
+    self.delete_deferred()
+  }
+
+  /// Deletes every entry pushed longer than `age` ago, whether it was read
+  /// or not, and returns how many were removed, like `trim_before`.
+  ///
+  /// Entries are pushed in order, so this stops at the first one young
+  /// enough to keep. Entries written by a version that did not record push
+  /// times are removed along with any later entry that is too old, and kept
+  /// otherwise.
+  pub fn prune_older_than(&mut self, age: Duration) -> Result<u64, Error> {
+    let cutoff = now_millis().saturating_sub(age.as_millis() as u64);
+    let mut bound = None;
+    for (key, data) in Journal::entries_in(&self.db, KeyType::Timestamp, 0, Some(self.head.id)) {
+      match decode_u64(&data) {
+        Some(pushed_at) if pushed_at < cutoff => bound = Some(key.id + 1),
+        _ => break
+      }
+    }
+    match bound {
+      Some(bound) => self.trim_before(bound),
+      None => Ok(0)
+    }
+  }
+
+  /// Renumbers all live entries, reserved and ready, consecutively from 0,
+  /// keeping their order. This restores the id space after `push` failed
+  /// with `Error::IdSpaceExhausted`.
+  ///
+  /// All entries are rewritten in a single atomic batch, so this needs
+  /// memory proportional to the size of the journal.
+  pub fn compact_ids(&mut self) -> Result<(), Error> {
+    if self.deferred > 0 {
+      self.delete_deferred()?;
+    }
+    let entries: Vec<(Key, Vec<u8>)> = Journal::entries_in(&self.db, KeyType::Queue, 0, None).collect();
+
+    // new ids never exceed old ones, so moving entries in order never
+    // overwrites one that has not been moved yet
+    let mut batch = Writebatch::new();
+    let mut tail = 0;
+    let mut reserved_tail = None;
+    let mut reserved_ahead = BTreeSet::new();
+    for (new_id, (key, data)) in entries.iter().enumerate() {
+      let new_id = new_id as Id;
+      if self.reserved_ahead.contains(&key.id) {
+        reserved_ahead.insert(new_id);
+      }
+      if key.id < self.tail.id {
+        tail = new_id + 1;
+        if key.id >= self.reserved_tail.id && reserved_tail.is_none() {
+          reserved_tail = Some(new_id);
+        }
+      }
+      self.move_entry(&mut batch, key.id, new_id, data)?;
+    }
+    batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(entries.len() as Id));
+
+    self.write(&batch)?;
+
+    self.head = Key::new(KeyType::Queue, entries.len() as Id);
+    self.tail = Key::new(KeyType::Queue, tail);
+    self.reserved_tail = Key::new(KeyType::Queue, reserved_tail.unwrap_or(tail));
+    self.reserved_ahead = reserved_ahead;
+    Ok(())
+  }
+
+  /// Renumbers all live entries consecutively from 0 like `compact_ids`,
+  /// but moves them one at a time instead of in one batch, so it works on
+  /// journals of any size.
+  ///
+  /// Every entry moves in its own atomic write, so no entry is ever lost or
+  /// duplicated. If a write fails or the process dies part way, the journal
+  /// is left partly renumbered and the rest of the work is done on the next
+  /// call or the next open. After a failed write, reserved entries are
+  /// handed out again, just like after a restart.
+  pub fn defragment(&mut self) -> Result<(), Error> {
+    let result = self.defragment_entries();
+    if result.is_err() {
+      let (head, tail, reserved_tail) = Journal::read_keys(&self.db)?;
+      self.head = head;
+      self.tail = tail;
+      self.reserved_tail = reserved_tail;
+      self.in_flight = 0;
+      self.reserved_ahead.clear();
+    }
+    result
+  }
+
+  fn defragment_entries(&mut self) -> Result<(), Error> {
+    if self.deferred > 0 {
+      self.delete_deferred()?;
+    }
+    let mut count = 0;
+    let mut tail = 0;
+    let mut reserved_tail = None;
+    let mut reserved_ahead = BTreeSet::new();
+    // new ids never exceed old ones, so moving entries in order never
+    // overwrites one that has not been moved yet
+    for (key, data) in Journal::entries_in(&self.db, KeyType::Queue, 0, None) {
+      let new_id = count;
+      count += 1;
+      if self.reserved_ahead.contains(&key.id) {
+        reserved_ahead.insert(new_id);
+      }
+      if key.id < self.tail.id {
+        tail = new_id + 1;
+        if key.id >= self.reserved_tail.id && reserved_tail.is_none() {
+          reserved_tail = Some(new_id);
+        }
+      }
+      if key.id == new_id {
+        continue
+      }
+
+      let mut batch = Writebatch::new();
+      self.move_entry(&mut batch, key.id, new_id, &data)?;
+      batch.put(Key::new(KeyType::Meta, DEFRAGMENT_META), &encode_u64(1));
+      self.write(&batch)?;
+    }
+
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Meta, HEAD_META), &encode_u64(count));
+    batch.delete(Key::new(KeyType::Meta, DEFRAGMENT_META));
+    self.write(&batch)?;
+
+    self.head = Key::new(KeyType::Queue, count);
+    self.tail = Key::new(KeyType::Queue, tail);
+    self.reserved_tail = Key::new(KeyType::Queue, reserved_tail.unwrap_or(tail));
+    self.reserved_ahead = reserved_ahead;
+    Ok(())
+  }
+
+  /// Whether both journals hold the same entries ready to be popped, in the
+  /// same order, no matter under which ids. Reads both side by side, one
+  /// entry at a time.
+  pub fn contents_eq(&self, other: &Journal) -> Result<bool, Error> {
+    self.compare_contents(other, false)
+  }
+
+  /// Like `contents_eq`, but the entries also need to have the same ids.
+  pub fn contents_eq_with_ids(&self, other: &Journal) -> Result<bool, Error> {
+    self.compare_contents(other, true)
+  }
+
+  fn compare_contents(&self, other: &Journal, with_ids: bool) -> Result<bool, Error> {
+    let mut ours = self.ready();
+    let mut theirs = other.ready();
+    loop {
+      match (ours.next(), theirs.next()) {
+        (None, None) => return Ok(true),
+        (Some((key, data)), Some((other_key, other_data))) => {
+          if with_ids && key.id != other_key.id {
+            return Ok(false)
+          }
+          if self.decode_value(key.id, data)? != other.decode_value(other_key.id, other_data)? {
+            return Ok(false)
+          }
+        },
+        _ => return Ok(false)
+      }
+    }
+  }
+
+  /// How long popping the entries ready now takes at `rate_per_sec`
+  /// entries a second. None if there is nothing to pop, or if the rate is
+  /// not positive or so low that the journal would never drain.
+  pub fn drain_eta(&self, rate_per_sec: f64) -> Option<Duration> {
+    if self.is_empty() || rate_per_sec.is_nan() || rate_per_sec <= 0.0 {
+      return None
+    }
+    Duration::try_from_secs_f64(self.len() as f64 / rate_per_sec).ok()
+  }
+
+  /// Stores `value` under `key` next to the entries, for small bits of
+  /// application state that should stay with the journal. Two keys with the
+  /// same hash share a slot, the one set last wins.
+  pub fn set_user_meta(&mut self, key: &str, value: &[u8]) -> Result<(), Error> {
+    let mut record = encode_u64(key.len() as u64).to_vec();
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(value);
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::UserMeta, fnv1a(key.as_bytes())), &record);
+    self.write(&batch)?;
+    Ok(())
+  }
+
+  /// The value last set for `key` with `set_user_meta`.
+  pub fn get_user_meta(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    let read_options = ReadOptions::new();
+    let record = match self.db.get(read_options, Key::new(KeyType::UserMeta, fnv1a(key.as_bytes())))? {
+      Some(record) => record,
+      None => return Ok(None)
+    };
+    // the record starts with the key it was set under
+    let key_len = record.get(..8).and_then(decode_u64).unwrap_or(0) as usize;
+    match record.get(8..).and_then(|rest| rest.get(..key_len)) {
+      Some(stored_key) if stored_key == key.as_bytes() => Ok(Some(record[8 + key_len..].to_vec())),
+      _ => Ok(None)
+    }
+  }
+
+  /// How many entries were ever pushed to this journal, kept across
+  /// restarts. Journals written by a version that did not count them start
+  /// counting at 0.
+  pub fn total_pushed(&self) -> u64 {
+    self.total_pushed
+  }
+
+  /// How many entries were ever popped from this journal or acked, kept
+  /// across restarts like `total_pushed`.
+  pub fn total_popped(&self) -> u64 {
+    self.total_popped
+  }
+
+  /// The number of entries pushed but not popped yet, same as `len`.
+  pub fn lag(&self) -> u64 {
+    self.len()
+  }
+
+  /// The total size in bytes of the entries counted by `lag`.
+  pub fn lag_bytes(&self) -> Result<u64, Error> {
+    self.iter().map(|entry| entry.map(|(_, data)| data.len() as u64)).sum()
+  }
+
+  /// How long the oldest entry ready to be popped has been waiting, same as
+  /// `lag_duration`. Only reads that entry's push time.
+  pub fn oldest_age(&self) -> Result<Option<Duration>, Error> {
+    self.lag_duration()
+  }
+
+  /// How long the entry at the tail has been waiting since it was pushed.
+  ///
+  /// None if the journal is empty, or if the entry was written by a version
+  /// that did not record push times.
+  pub fn lag_duration(&self) -> Result<Option<Duration>, Error> {
+    let id = match self.first_ready_id() {
+      Some(id) => id,
+      None => return Ok(None)
+    };
+    let read_options = ReadOptions::new();
+    let pushed_at = self.db.get(read_options, Key::new(KeyType::Timestamp, id))?.and_then(|data| decode_u64(&data));
+    Ok(pushed_at.map(|pushed_at| Duration::from_millis(now_millis().saturating_sub(pushed_at))))
+  }
+
+  /// The number of entries ready to be popped.
+  pub fn len(&self) -> u64 {
+    self.stored - self.deferred - self.in_flight
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Like `len`, but counts the entries actually stored between tail and
+  /// head instead of trusting the counts kept with them. Slower, as it
+  /// walks every key.
+  pub fn len_verified(&self) -> Result<u64, Error> {
+    let keys = Journal::keys_in(&self.db, KeyType::Queue, self.tail.id, Some(self.head.id));
+    Ok(keys.filter(|key| !self.reserved_ahead.contains(&key.id)).count() as u64)
+  }
+
+  /// Estimates how many entries are ready to be popped, like `len`, from
+  /// the keys themselves instead of the stored count, without walking every
+  /// key like `len_verified`.
+  ///
+  /// It reads only the oldest and the newest stored entry, takes every id
+  /// between them to be an entry, and subtracts the entries in flight and
+  /// the popped ones waiting for deletion. Holes left by acking out of
+  /// order and by trimming around pinned entries make it count high.
+  pub fn approx_len(&self) -> Result<u64, Error> {
+    let first = Journal::keys_in(&self.db, KeyType::Queue, 0, None).next();
+    let last = Journal::last_key_of(&self.db, KeyType::Queue);
+    match (first, last) {
+      (Some(first), Some(last)) => Ok((last.id - first.id + 1).saturating_sub(self.deferred + self.in_flight)),
+      _ => Ok(0)
+    }
+  }
+
+  /// Lists the ranges of ids of entries that went missing without the
+  /// journal removing them, as first and last missing id, both inclusive.
+  /// That should never happen.
+  ///
+  /// Entries acked out of order, trimmed around pinned ones or popped leave
+  /// holes that are not gaps. The journal does not remember where those
+  /// are, only how many entries it holds, so reserved entries are checked
+  /// by their delivery records, and the holes among the ready entries are
+  /// only listed if fewer are stored than counted. Those lists then include
+  /// any holes the journal left there itself.
+  pub fn gaps(&self) -> Result<Vec<(Id, Id)>, Error> {
+    let mut missing = Vec::new();
+    for key in Journal::keys_in(&self.db, KeyType::Delivery, self.reserved_tail.id, Some(self.tail.id)) {
+      let read_options = ReadOptions::new();
+      if self.db.get(read_options, Key::new(KeyType::Queue, key.id))?.is_none() {
+        missing.push(key.id);
+      }
+    }
+    let stored = Journal::keys_in(&self.db, KeyType::Queue, self.reserved_tail.id, Some(self.head.id)).count() as u64;
+    if stored < self.stored - self.deferred {
+      let mut expected = self.tail.id;
+      for key in Journal::keys_in(&self.db, KeyType::Queue, self.tail.id, Some(self.head.id)) {
+        missing.extend(expected..key.id);
+        expected = key.id + 1;
+      }
+      missing.extend(expected..self.head.id);
+    }
+
+    let mut gaps: Vec<(Id, Id)> = Vec::new();
+    for id in missing {
+      match gaps.last_mut() {
+        Some(&mut (_, ref mut last)) if *last + 1 == id => *last = id,
+        _ => gaps.push((id, id))
+      }
+    }
+    Ok(gaps)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use super::{Key,KeyType,Journal,JournalOptions,ValueCipher,Event,Operation,Error,HEAD_META,DEFRAGMENT_META,FORMAT_META,FORMAT_VERSION,encode_u64,journal_comparator,validate_path};
+  use self::tempdir::TempDir;
+  use std::cmp::Ordering;
+  use std::io;
+  use std::sync::atomic::Ordering as AtomicOrdering;
+  use leveldb::database::batch::Writebatch;
+
+  #[test]
+  fn test_compare() {
+    let key = Key { keytype: KeyType::Queue, id: 123 };
+    let key2 = Key { keytype: KeyType::Chunk, id: 123 };
+    let key3 = Key { keytype: KeyType::Queue, id: 124 };
+    assert_eq!(Ordering::Less, key.cmp(&key2));
+    assert_eq!(Ordering::Greater, key2.cmp(&key));
+    assert_eq!(Ordering::Less, key.cmp(&key3));
+    assert_eq!(Ordering::Greater, key3.cmp(&key));
+    assert_eq!(Ordering::Equal, key.cmp(&key));
+  }
+
+  #[test]
+  fn test_equality() {
+    let key = Key { keytype: KeyType::Queue, id: 0 };
+    let key2 = Key { keytype: KeyType::Queue, id: 0 };
+    assert_eq!(Ordering::Equal, key.cmp(&key2));
+  }
+
+  #[test]
+  fn test_push() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    let res = journal.peek().unwrap();
+    assert!(res.is_some());
+  }
+
+  #[test]
+  fn test_journal() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    let res = journal.pop().unwrap();
+    assert!(res.is_none());
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    let res2 = journal.pop().unwrap();
+    assert!(res2.is_some());
+    assert_eq!(Some(vec![1u8]), res2);
+    let res3 = journal.pop().unwrap();
+    assert!(res3.is_some());
+    assert_eq!(Some(vec![2u8]), res3);
+    let res4 = journal.pop().unwrap();
+    assert!(res4.is_none());
+    assert_eq!(0, journal.len());
+  }
+
+  #[test]
+  fn test_reserve_ack() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    let (first, data) = journal.reserve().unwrap().unwrap();
+    assert_eq!(vec![1u8], data);
+    assert_eq!(1, journal.attempts(first).unwrap());
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+    assert!(journal.ack(first).unwrap());
+    assert!(!journal.ack(first).unwrap());
+    assert!(journal.reserve().unwrap().is_none());
+  }
+
+  #[test]
+  fn test_dead_letter() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let dead_dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.set_dead_letter(Journal::open(dead_dir.path()).unwrap(), 2);
+    journal.push(&[1u8]).unwrap();
+
+    let (id, _) = journal.reserve().unwrap().unwrap();
+    assert!(journal.nack(id).unwrap());
+    let (id, data) = journal.reserve().unwrap().unwrap();
+    assert_eq!(vec![1u8], data);
+    assert_eq!(2, journal.attempts(id).unwrap());
+    assert!(journal.nack(id).unwrap());
+
+    assert!(journal.reserve().unwrap().is_none());
+    assert!(journal.pop().unwrap().is_none());
+    assert_eq!(Some(vec![1u8]), journal.dead_letter().unwrap().peek().unwrap());
+  }
+
+  #[test]
+  fn test_dead_letter_failed_remove() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let dead_dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.set_dead_letter(Journal::open(dead_dir.path()).unwrap(), 1);
+    journal.push(&[1u8]).unwrap();
+
+    let (id, _) = journal.reserve().unwrap().unwrap();
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    match journal.nack(id) {
+      Err(Error::Io(ref err)) => assert!(err.to_string().contains("copy was removed again"), "{}", err),
+      other => panic!("expected an I/O error, got {:?}", other)
+    }
+    assert_eq!(0, journal.dead_letter().unwrap().len());
+    assert!(journal.is_reserved(id).unwrap());
+
+    assert!(journal.nack(id).unwrap());
+    assert_eq!(1, journal.dead_letter().unwrap().len());
+    assert_eq!(vec![vec![1u8]], journal.dead_letter().unwrap().iter().map(Result::unwrap).map(|(_, data)| data).collect::<Vec<_>>());
+    assert_eq!(None, journal.get(id).unwrap());
+  }
+
+  #[test]
+  fn test_reopen() {
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      let mut journal = Journal::open(dir.path()).unwrap();
+      journal.push(&[1u8]).unwrap();
+      journal.push(&[2u8]).unwrap();
+      journal.push(&[3u8]).unwrap();
+      journal.pop().unwrap();
+    }
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(2, journal.len());
+    journal.push(&[4u8]).unwrap();
+    let values: Vec<Vec<u8>> = journal.iter().map(Result::unwrap).map(|(_, data)| data).collect();
+    assert_eq!(vec![vec![2u8], vec![3u8], vec![4u8]], values);
+  }
+
+  #[test]
+  fn test_iteration_bounded_by_keytype() {
+    use leveldb::database::kv::KV;
+    use leveldb::options::WriteOptions;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..3u8 {
+      journal.push(&[i]).unwrap();
+    }
+    for i in 0..5 {
+      journal.db.put(WriteOptions::new(), Key::new(KeyType::Chunk, i), &[9u8]).unwrap();
+      journal.db.put(WriteOptions::new(), Key::new(KeyType::Delivery, i), &[9u8]).unwrap();
+    }
+
+    let mut range = Journal::keys_in(&journal.db, KeyType::Queue, 0, None);
+    let keys: Vec<Key> = range.by_ref().collect();
+    assert_eq!(3, keys.len());
+    assert!(keys.iter().all(|key| key.keytype == KeyType::Queue));
+    // the three queue keys, plus the first chunk key that ends the range
+    assert_eq!(4, range.visited);
+
+    assert_eq!(3, journal.iter().map(Result::unwrap).count());
+    assert_eq!(Some(Key::new(KeyType::Queue, 2)), Journal::last_key_of(&journal.db, KeyType::Queue));
+    assert_eq!(Some(Key::new(KeyType::Delivery, 4)), Journal::last_key_of(&journal.db, KeyType::Delivery));
+  }
+
+  #[test]
+  fn test_relocate() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let old_path = dir.path().join("old");
+    let new_path = dir.path().join("new");
+    let mut journal = Journal::open(&old_path).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+
+    let mut journal = journal.relocate(&new_path).unwrap();
+    assert!(!old_path.exists());
+    assert_eq!(new_path.as_path(), journal.path());
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+
+    let other = Journal::open(&old_path).unwrap();
+    assert!(other.relocate(&new_path).is_err());
+
+    let mut options = JournalOptions::new();
+    options.offload_threshold = Some(4);
+    let mut journal = Journal::open_with_options(&dir.path().join("offloaded"), options).unwrap();
+    journal.push(&[1u8; 10]).unwrap();
+    let moved_path = dir.path().join("moved");
+    let mut journal = journal.relocate(&moved_path).unwrap();
+    journal.push(&[2u8; 10]).unwrap();
+    assert_eq!(Some(vec![1u8; 10]), journal.pop().unwrap());
+    assert_eq!(Some(vec![2u8; 10]), journal.pop().unwrap());
+    assert_eq!(20, std::fs::metadata(moved_path.join(super::OFFLOAD_FILE)).unwrap().len());
+  }
+
+  #[test]
+  fn test_split_at() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let second_path = dir.path().join("second");
+    let mut journal = Journal::open(&dir.path().join("first")).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    assert_eq!(Some(vec![0u8]), journal.pop().unwrap());
+    assert_eq!(Some((1, vec![1u8])), journal.reserve().unwrap());
+
+    let (mut first, mut second) = journal.split_at(5, &second_path).unwrap();
+    assert_eq!(3, first.len());
+    assert_eq!(vec![vec![2u8], vec![3u8], vec![4u8]], first.peek_all().unwrap());
+    assert!(first.ack(1).unwrap());
+    assert_eq!(5, first.next_id());
+    assert_eq!((0..5).map(|i| vec![5 + i as u8]).collect::<Vec<_>>(), second.peek_all().unwrap());
+    assert_eq!(Some((0, vec![5u8])), second.reserve().unwrap());
+
+    let other = Journal::open(&dir.path().join("other")).unwrap();
+    assert!(other.split_at(0, &second_path).is_err());
+  }
+
+  #[test]
+  fn test_merge_from() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(&dir.path().join("journal")).unwrap();
+    let mut other = Journal::open(&dir.path().join("other")).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    for i in 3..6u8 {
+      other.push(&[i]).unwrap();
+    }
+    assert_eq!(Some((0, vec![3u8])), other.reserve().unwrap());
+
+    assert_eq!(3, journal.merge_from(&mut other).unwrap());
+    assert_eq!(5, journal.len());
+    assert_eq!((1..6).map(|i| vec![i as u8]).collect::<Vec<_>>(), journal.peek_all().unwrap());
+    assert_eq!(0, other.len());
+    assert!(!other.ack(0).unwrap());
+    assert_eq!(None, other.pop().unwrap());
+    assert_eq!(0, journal.merge_from(&mut other).unwrap());
+  }
+
+  #[test]
+  fn test_subscribe_events() {
+    use super::CursorEvent;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    let events = journal.subscribe_events();
+    let other = journal.subscribe_events();
+    journal.push(&[1u8]).unwrap();
+    drop(other);
+    journal.push(&[2u8]).unwrap();
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert_eq!(Some((1, vec![2u8])), journal.reserve().unwrap());
+
+    let received: Vec<CursorEvent> = events.try_iter().collect();
+    assert_eq!(vec![
+      CursorEvent { operation: Operation::Push, head: 1, tail: 0 },
+      CursorEvent { operation: Operation::Push, head: 2, tail: 0 },
+      CursorEvent { operation: Operation::Pop, head: 2, tail: 1 }
+    ], received);
+    // the dropped receiver was unsubscribed by the next push
+    assert_eq!(1, journal.subscribers.lock().unwrap().len());
+  }
+
+  #[test]
+  fn test_subscribers_survive_poisoning() {
+    use std::thread;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    let events = journal.subscribe_events();
+    thread::scope(|scope| {
+      let poisoner = scope.spawn(|| {
+        let _subscribers = journal.subscribers.lock();
+        panic!("poisoning the subscribers");
+      });
+      assert!(poisoner.join().is_err());
+    });
+    assert!(journal.subscribers.is_poisoned());
+
+    journal.push(&[1u8]).unwrap();
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert_eq!(2, events.try_iter().count());
+    let _more = journal.subscribe_events();
+  }
+
+  #[test]
+  fn test_retry_interrupted_write() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.interrupted_writes.store(1, AtomicOrdering::SeqCst);
+    assert_eq!(0, journal.push(&[1u8]).unwrap());
+    journal.interrupted_writes.store(1, AtomicOrdering::SeqCst);
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert!(journal.is_empty());
+
+    // only once, a second interruption is reported
+    journal.interrupted_writes.store(2, AtomicOrdering::SeqCst);
+    assert!(journal.push(&[2u8]).is_err());
+    assert_eq!(0, journal.interrupted_writes.load(AtomicOrdering::SeqCst));
+  }
+
+  #[test]
+  fn test_push_returns_id() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(0, journal.push(&[1u8]).unwrap());
+    journal.pop().unwrap();
+    assert_eq!(1, journal.push(&[2u8]).unwrap());
+    assert_eq!(2, journal.push(&[3u8]).unwrap());
+    drop(journal);
+
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(3, journal.push(&[4u8]).unwrap());
+  }
+
+  #[test]
+  fn test_open_locked() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+
+    match Journal::open(dir.path()) {
+      Err(Error::Locked(path)) => assert_eq!(dir.path(), path.as_path()),
+      Err(other) => panic!("expected Error::Locked, got {:?}", other),
+      Ok(_) => panic!("expected Error::Locked, opened a second handle")
+    }
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_open_with_retry() {
+    use std::thread;
+    use std::time::Duration;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    match Journal::open_with_retry(dir.path(), 3, Duration::from_millis(1)) {
+      Err(Error::Locked(_)) => (),
+      Err(other) => panic!("expected Error::Locked, got {:?}", other),
+      Ok(_) => panic!("expected Error::Locked, opened a second handle")
+    }
+
+    // the holder lets go while the retries are still going
+    let holder = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(30));
+      drop(journal);
+    });
+    let mut journal = Journal::open_with_retry(dir.path(), 10, Duration::from_millis(5)).unwrap();
+    holder.join().unwrap();
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_next_id() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(0, journal.next_id());
+    journal.push(&[1u8]).unwrap();
+    let next = journal.next_id();
+    assert_eq!(next, journal.push(&[2u8]).unwrap());
+    assert_eq!(next + 1, journal.next_id());
+  }
+
+  #[test]
+  fn test_validate_path() {
+    use std::path::Path;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    assert!(validate_path(dir.path()).is_ok());
+    for path in [Path::new(""), Path::new("journal\0name")].iter() {
+      match Journal::open(path) {
+        Err(error @ Error::InvalidPath(..)) => assert!(error.to_string().starts_with("can not open a journal at")),
+        other => panic!("expected InvalidPath, got {:?}", other.err())
+      }
+    }
+
+    #[cfg(unix)]
+    {
+      use std::ffi::OsStr;
+      use std::os::unix::ffi::OsStrExt;
+
+      let path = dir.path().join(OsStr::from_bytes(&[b'j', 0xff]));
+      match validate_path(&path) {
+        Err(Error::InvalidPath(_, reason)) => assert_eq!("path is not valid UTF-8", reason),
+        other => panic!("expected InvalidPath, got {:?}", other)
+      }
+    }
+  }
+
+  #[test]
+  fn test_swap() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let blue_path = dir.path().join("blue");
+    let green_path = dir.path().join("green");
+    let named = |name: &str| {
+      let mut options = JournalOptions::new();
+      options.name = Some(name.to_string());
+      options
+    };
+    let mut blue = Journal::open_with_options(&blue_path, named("blue")).unwrap();
+    let mut green = Journal::open_with_options(&green_path, named("green")).unwrap();
+    blue.push(&[1u8]).unwrap();
+    green.push(&[2u8]).unwrap();
+    green.push(&[3u8]).unwrap();
+
+    blue.swap(&mut green);
+    assert_eq!(green_path.as_path(), blue.path());
+    assert_eq!(blue_path.as_path(), green.path());
+    assert_eq!(Some("green"), blue.name());
+    assert_eq!(Some("blue"), green.name());
+    assert_eq!(2, blue.metrics().pushes);
+    assert_eq!(vec![(0, vec![2u8]), (1, vec![3u8])], blue.iter().map(Result::unwrap).collect::<Vec<_>>());
+    assert_eq!(vec![(0, vec![1u8])], green.iter().map(Result::unwrap).collect::<Vec<_>>());
+    assert_eq!(Some(vec![2u8]), blue.pop().unwrap());
+    assert_eq!(Some(vec![1u8]), green.pop().unwrap());
+  }
+
+  #[test]
+  fn test_observer() {
+    use std::sync::{Arc,Mutex};
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    journal.set_observer(Box::new(move |event| recorded.lock().unwrap().push(event)));
+
+    journal.push(&[1u8, 2u8]).unwrap();
+    journal.push(&[3u8]).unwrap();
+    journal.pop().unwrap();
+    journal.pop().unwrap();
+    journal.pop().unwrap();
+
+    let expected = vec![
+      Event { operation: Operation::Push, id: 0, size: 2, journal: None },
+      Event { operation: Operation::Push, id: 1, size: 1, journal: None },
+      Event { operation: Operation::Pop, id: 0, size: 2, journal: None },
+      Event { operation: Operation::Pop, id: 1, size: 1, journal: None },
+    ];
+    assert_eq!(expected, *events.lock().unwrap());
+  }
+
+  #[test]
+  fn test_push_vectored() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(0, journal.push_vectored(&[b"head", b"", b"er", b"body"]).unwrap());
+    assert_eq!(1, journal.push_vectored(&[]).unwrap());
+    assert_eq!(Some(b"headerbody".to_vec()), journal.pop().unwrap());
+    assert_eq!(Some(Vec::new()), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_deferred_deletes() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let options = || {
+      let mut options = JournalOptions::new();
+      options.deferred_deletes = Some(4);
+      options
+    };
+    let mut journal = Journal::open_with_options(dir.path(), options()).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    for i in 0..6u8 {
+      assert_eq!(Some(vec![i]), journal.pop().unwrap());
+    }
+    // the first four were deleted together, the next two are still there
+    assert_eq!(None, journal.get(3).unwrap());
+    assert_eq!(Some(vec![5u8]), journal.get(5).unwrap());
+    assert_eq!(4, journal.len());
+
+    // with an entry in flight, pop deletes right away
+    assert_eq!(Some((6, vec![6u8])), journal.reserve().unwrap());
+    assert_eq!(Some(vec![7u8]), journal.pop().unwrap());
+    assert_eq!(None, journal.get(7).unwrap());
+    assert!(journal.ack(6).unwrap());
+    drop(journal);
+
+    let mut journal = Journal::open_with_options(dir.path(), options()).unwrap();
+    assert_eq!(None, journal.get(5).unwrap());
+    assert_eq!(8, journal.total_popped());
+    assert_eq!(2, journal.len());
+    assert_eq!(Some(vec![8u8]), journal.pop().unwrap());
+    journal.flush().unwrap();
+    assert_eq!(None, journal.get(8).unwrap());
+    assert_eq!(Some(vec![9u8]), journal.pop().unwrap());
+    assert_eq!(None, journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_purge_consumed() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.deferred_deletes = Some(100);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    for i in 0..6u8 {
+      journal.push(&[i]).unwrap();
+    }
+    for _ in 0..3 {
+      journal.pop().unwrap();
+    }
+    assert_eq!(Some((3, vec![3u8])), journal.reserve().unwrap());
+    assert_eq!(Some(vec![2u8]), journal.get(2).unwrap());
+
+    assert_eq!(3, journal.purge_consumed().unwrap());
+    let stored: Vec<u64> = Journal::keys_in(&journal.db, KeyType::Queue, 0, None).map(|key| key.id).collect();
+    assert_eq!(vec![3, 4, 5], stored);
+    assert_eq!(0, journal.purge_consumed().unwrap());
+    assert!(journal.ack(3).unwrap());
+    assert_eq!(2, journal.len());
+  }
+
+  #[test]
+  fn test_pop_into() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..5u8 {
+      journal.push(&vec![i; i as usize + 1]).unwrap();
+    }
+    let mut buf = Vec::with_capacity(16);
+    for i in 0..5u8 {
+      assert!(journal.pop_into(&mut buf).unwrap());
+      assert_eq!(vec![i; i as usize + 1], buf);
+    }
+    assert!(!journal.pop_into(&mut buf).unwrap());
+    assert!(buf.is_empty());
+    assert_eq!(16, buf.capacity());
+  }
+
+  #[test]
+  fn test_peek_at() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..3u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.pop().unwrap();
+    assert_eq!(Some(vec![1u8]), journal.peek_at(0).unwrap());
+    assert_eq!(Some(vec![2u8]), journal.peek_at(1).unwrap());
+    assert_eq!(None, journal.peek_at(2).unwrap());
+    assert_eq!(None, journal.peek_at(u64::MAX).unwrap());
+  }
+
+  #[test]
+  fn test_iter_rev() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(0, journal.iter_rev().map(Result::unwrap).count());
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.pop().unwrap();
+    // records of other keyspaces sort after the entries
+    journal.set_user_meta("owner", b"me").unwrap();
+
+    let reversed: Vec<(u64, Vec<u8>)> = journal.iter_rev().map(Result::unwrap).collect();
+    assert_eq!((1..5).rev().map(|i| (i, vec![i as u8])).collect::<Vec<_>>(), reversed);
+    let forward: Vec<(u64, Vec<u8>)> = journal.iter().map(Result::unwrap).collect();
+    assert_eq!((1..5).map(|i| (i, vec![i as u8])).collect::<Vec<_>>(), forward);
+    assert_eq!(4, journal.len());
+  }
+
+  #[test]
+  fn test_iter_is_stable() {
+    use std::thread;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    let mut iter = journal.iter().map(Result::unwrap);
+    assert_eq!(Some((0, vec![0u8])), iter.next());
+    thread::scope(|scope| {
+      scope.spawn(|| {
+        let mut batch = Writebatch::new();
+        batch.delete(Key::new(KeyType::Queue, 3));
+        batch.put(Key::new(KeyType::Queue, 2), &[9u8]);
+        batch.put(Key::new(KeyType::Queue, 5), &[5u8]);
+        journal.write(&batch).unwrap();
+      });
+    });
+    let rest: Vec<_> = iter.collect();
+    assert_eq!(vec![(1, vec![1u8]), (2, vec![2u8]), (3, vec![3u8]), (4, vec![4u8])], rest);
+    assert_eq!(4, journal.iter().map(Result::unwrap).count());
+  }
+
+  #[test]
+  fn test_iter_reads_offload_markers_from_snapshot() {
+    use std::thread;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.offload_threshold = Some(4);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    for i in 0..3u8 {
+      journal.push(&[i; 10]).unwrap();
+    }
+    let mut iter = journal.iter().map(Result::unwrap);
+    assert_eq!(Some((0, vec![0u8; 10])), iter.next());
+    thread::scope(|scope| {
+      scope.spawn(|| {
+        let mut batch = Writebatch::new();
+        batch.delete(Key::new(KeyType::Queue, 1));
+        batch.delete(Key::new(KeyType::Offloaded, 1));
+        journal.write(&batch).unwrap();
+      });
+    });
+    let rest: Vec<_> = iter.collect();
+    assert_eq!(vec![(1, vec![1u8; 10]), (2, vec![2u8; 10])], rest);
+  }
+
+  #[test]
+  fn test_peek_range() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.pop().unwrap();
+    assert_eq!(vec![vec![3u8], vec![4u8], vec![5u8]], journal.peek_range(2, 3).unwrap());
+    assert_eq!(vec![vec![8u8], vec![9u8]], journal.peek_range(7, 5).unwrap());
+    assert!(journal.peek_range(u64::MAX, 5).unwrap().is_empty());
+    assert_eq!(9, journal.peek_all().unwrap().len());
+
+    // one pass, visiting nothing but the entries read
+    let mut range = journal.ready_from(2);
+    assert_eq!(3, range.by_ref().take(3).count());
+    assert_eq!(3, range.visited);
+  }
+
+  #[test]
+  fn test_peek_cow() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(None, journal.peek_cow().unwrap());
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    assert_eq!(journal.peek().unwrap().unwrap(), &*journal.peek_cow().unwrap().unwrap());
+    journal.pop().unwrap();
+    assert_eq!(&[2u8][..], &*journal.peek_cow().unwrap().unwrap());
+  }
+
+  #[test]
+  fn test_peek_ends() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    let (oldest, newest) = journal.peek_ends(2).unwrap();
+    assert_eq!(vec![vec![0u8], vec![1u8]], oldest);
+    assert_eq!(vec![vec![8u8], vec![9u8]], newest);
+
+    journal.pop_batch(7).unwrap();
+    let (oldest, newest) = journal.peek_ends(2).unwrap();
+    assert_eq!(vec![vec![7u8], vec![8u8]], oldest);
+    assert_eq!(vec![vec![8u8], vec![9u8]], newest);
+    let (oldest, newest) = journal.peek_ends(5).unwrap();
+    assert_eq!(3, oldest.len());
+    assert_eq!(oldest, newest);
+    assert_eq!((vec![], vec![]), journal.peek_ends(0).unwrap());
+  }
+
+  #[test]
+  fn test_digest() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut first = Journal::open(&dir.path().join("first")).unwrap();
+    let mut second = Journal::open(&dir.path().join("second")).unwrap();
+    for journal in [&mut first, &mut second].iter_mut() {
+      journal.push(&[1u8]).unwrap();
+      journal.push(&[2u8, 3u8]).unwrap();
+    }
+    second.compact_reserved();
+    assert_eq!(first.digest().unwrap(), second.digest().unwrap());
+
+    second.push(&[4u8]).unwrap();
+    assert!(first.digest().unwrap() != second.digest().unwrap());
+  }
+
+  #[test]
+  fn test_has_next() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert!(!journal.has_next());
+    journal.push(&[1u8]).unwrap();
+
+    assert!(journal.has_next());
+    assert_eq!(0, journal.value_reads.load(AtomicOrdering::SeqCst));
+    assert_eq!(Some(vec![1u8]), journal.peek().unwrap());
+    assert_eq!(1, journal.value_reads.load(AtomicOrdering::SeqCst));
+    assert!(journal.has_next());
+    assert_eq!(1, journal.value_reads.load(AtomicOrdering::SeqCst));
+
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert!(!journal.has_next());
+  }
+
+  #[test]
+  fn test_id_space_exhausted() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.head.id = u64::MAX - 2;
+    journal.tail.id = u64::MAX - 2;
+    journal.reserved_tail.id = u64::MAX - 2;
+
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    match journal.push(&[3u8]) {
+      Err(Error::IdSpaceExhausted) => (),
+      other => panic!("expected IdSpaceExhausted, got {:?}", other)
+    }
+
+    journal.compact_ids().unwrap();
+    assert_eq!(2, journal.head.id);
+    assert_eq!(0, journal.tail.id);
+    journal.push(&[3u8]).unwrap();
+    let values: Vec<Vec<u8>> = journal.iter().map(Result::unwrap).map(|(_, data)| data).collect();
+    assert_eq!(vec![vec![1u8], vec![2u8], vec![3u8]], values);
+  }
+
+  #[test]
+  fn test_get() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    assert_eq!(Some(vec![3u8]), journal.get(3).unwrap());
+    journal.pop().unwrap();
+    journal.pop().unwrap();
+    assert_eq!(Some(vec![3u8]), journal.get(3).unwrap());
+    assert_eq!(None, journal.get(0).unwrap());
+    assert_eq!(None, journal.get(5).unwrap());
+  }
+
+  #[test]
+  fn test_get_many() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.pop().unwrap();
+
+    let found = journal.get_many(&[3, 9, 0, 1, 3, u64::MAX]).unwrap();
+    assert_eq!(vec![Some(vec![3u8]), None, None, Some(vec![1u8]), Some(vec![3u8]), None], found);
+    assert!(journal.get_many(&[]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_push_writes_head_atomically() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    let stored_head = |journal: &Journal| Journal::read_cursor(&journal.db, HEAD_META).unwrap();
+    journal.push(&[1u8]).unwrap();
+    assert_eq!(Some(1), stored_head(&journal));
+
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.push(&[2u8]).is_err());
+    assert_eq!(None, journal.get(1).unwrap());
+    assert_eq!(Some(1), stored_head(&journal));
+    assert_eq!(1, journal.head.id);
+
+    journal.push(&[2u8]).unwrap();
+    assert_eq!(Some(vec![2u8]), journal.get(1).unwrap());
+    assert_eq!(Some(2), stored_head(&journal));
+  }
+
+  #[test]
+  fn test_error_handler() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool,AtomicUsize};
+    use super::ErrorAction;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    let errors = Arc::new(AtomicUsize::new(0));
+    let retry = Arc::new(AtomicBool::new(true));
+    let (counted, retried) = (errors.clone(), retry.clone());
+    journal.set_error_handler(Box::new(move |err| {
+      assert!(err.to_string().contains("injected write failure"));
+      counted.fetch_add(1, AtomicOrdering::SeqCst);
+      if retried.load(AtomicOrdering::SeqCst) { ErrorAction::Retry } else { ErrorAction::Fail }
+    }));
+
+    journal.failing_writes.store(2, AtomicOrdering::SeqCst);
+    assert_eq!(0, journal.push(&[1u8]).unwrap());
+    assert_eq!(2, errors.load(AtomicOrdering::SeqCst));
+
+    retry.store(false, AtomicOrdering::SeqCst);
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.push(&[2u8]).is_err());
+    assert_eq!(3, errors.load(AtomicOrdering::SeqCst));
+    assert_eq!(1, journal.len());
+  }
+
+  #[test]
+  fn test_name_in_errors() {
+    use std::sync::{Arc,Mutex};
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.name = Some("orders".to_string());
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    assert_eq!(Some("orders"), journal.name());
+    assert!(format!("{:?}", journal).contains("\"orders\""));
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    journal.set_observer(Box::new(move |event| recorded.lock().unwrap().push(event)));
+    journal.push(&[1u8]).unwrap();
+
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    match journal.reserve() {
+      Err(err) => assert!(err.to_string().contains("journal \"orders\": "), "{}", err),
+      other => panic!("expected an error, got {:?}", other)
+    }
+
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    match journal.push(&[2u8]) {
+      Err(err @ Error::Database(_)) => {
+        assert_eq!("LevelDB error: journal \"orders\": injected write failure", err.to_string())
+      },
+      other => panic!("expected a database error, got {:?}", other)
+    }
+
+    let events = events.lock().unwrap();
+    assert_eq!(Operation::Error, events[1].operation);
+    assert!(events.iter().all(|event| event.journal.as_deref() == Some("orders")));
+  }
+
+  #[test]
+  fn test_reopen_keeps_head_of_drained_journal() {
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      let mut journal = Journal::open(dir.path()).unwrap();
+      journal.push(&[1u8]).unwrap();
+      journal.push(&[2u8]).unwrap();
+      journal.pop().unwrap();
+      journal.pop().unwrap();
+    }
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(0, journal.len());
+    journal.push(&[3u8]).unwrap();
+    assert_eq!(Some(vec![3u8]), journal.get(2).unwrap());
+  }
+
+  #[test]
+  fn test_open_with_journal_comparator() {
+    use leveldb::database::Database;
+    use leveldb::database::kv::KV;
+    use leveldb::options::{Options,ReadOptions};
+
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      let mut journal = Journal::open(dir.path()).unwrap();
+      journal.push(&[1u8]).unwrap();
+      journal.push(&[2u8]).unwrap();
+    }
+    let db: Database<Key> = Database::open_with_comparator(dir.path(), Options::new(), journal_comparator()).unwrap();
+    let value = db.get(ReadOptions::new(), Key::new(KeyType::Queue, 1)).unwrap();
+    assert_eq!(Some(vec![2u8]), value);
+  }
+
+  #[test]
+  fn test_malformed_keys() {
+    use key::Key as StorageKey;
+    use leveldb::database::Database;
+    use leveldb::database::comparator::OrdComparator;
+    use leveldb::database::kv::KV;
+    use leveldb::options::{Options,WriteOptions};
+
+    assert_eq!(Key::new(KeyType::Queue, 0), Key::from_u8(&[]));
+    assert_eq!(Key::new(KeyType::Meta, 0), Key::from_u8(&[3u8]));
+    let mut long = vec![2u8, 0, 0, 0, 0, 0, 0, 0];
+    long.extend_from_slice(&5u64.to_ne_bytes());
+    long.extend_from_slice(&[1u8, 2u8]);
+    assert_eq!(Key::new(KeyType::Delivery, 5), Key::from_u8(&long));
+    assert_eq!(Key::new(KeyType::Unknown, u64::MAX), Key::from_u8(&[0xffu8; 16]));
+    let mut unknown = 99u64.to_ne_bytes().to_vec();
+    unknown.extend_from_slice(&0u64.to_ne_bytes());
+    assert_eq!(Key::new(KeyType::Unknown, 0), Key::from_u8(&unknown));
+    assert_eq!(Ordering::Greater, Key::from_u8(&unknown).cmp(&Key::new(KeyType::Pinned, u64::MAX)));
+
+    // a 4 byte key written by something else goes through our comparator
+    // when the journal replays the log
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      let mut options = Options::new();
+      options.create_if_missing = true;
+      let comparator: OrdComparator<i32> = OrdComparator::new(super::COMPARATOR_NAME);
+      let db: Database<i32> = Database::open_with_comparator(dir.path(), options, comparator).unwrap();
+      db.put(WriteOptions::new(), 77, &[1u8]).unwrap();
+    }
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[2u8]).unwrap();
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_key_encoding_does_not_sort_bytewise() {
+    use key::Key as StorageKey;
+
+    let keys = vec![Key::new(KeyType::Queue, 1), Key::new(KeyType::Queue, 256), Key::new(KeyType::Delivery, 1)];
+    let mut bytewise = keys.clone();
+    bytewise.sort_by(|a, b| a.as_slice(|a| b.as_slice(|b| a.cmp(b))));
+    assert!(bytewise != keys);
+  }
+
+  #[test]
+  fn test_approx_len() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(0, journal.approx_len().unwrap());
+    for i in 0..100u8 {
+      journal.push(&[i]).unwrap();
+    }
+    for _ in 0..10 {
+      journal.pop().unwrap();
+    }
+    assert_eq!(90, journal.approx_len().unwrap());
+
+    // entries in flight are left out, acked holes in the reserved block
+    // are counted
+    let reserved: Vec<u64> = (0..10).map(|_| journal.reserve().unwrap().unwrap().0).collect();
+    for id in reserved.into_iter().skip(1) {
+      assert!(journal.ack(id).unwrap());
+    }
+    let estimate = journal.approx_len().unwrap();
+    assert_eq!(80, journal.len());
+    assert_eq!(89, estimate);
+    assert!(estimate >= journal.len() && estimate <= journal.len() * 2);
+
+    let mut options = JournalOptions::new();
+    options.deferred_deletes = Some(100);
+    let deferred_dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open_with_options(deferred_dir.path(), options).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    for _ in 0..4 {
+      journal.pop().unwrap();
+    }
+    assert_eq!(4, journal.deferred);
+    assert_eq!(6, journal.approx_len().unwrap());
+  }
+
+  #[test]
+  fn test_len_verified() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    journal.push(&[3u8]).unwrap();
+    journal.pop().unwrap();
+    assert_eq!(2, journal.len_verified().unwrap());
+
+    journal.stored = 4;
+    assert_eq!(4, journal.len());
+    assert_eq!(2, journal.len_verified().unwrap());
+  }
+
+  #[test]
+  fn test_pop_batch() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    journal.push(&[3u8]).unwrap();
+
+    assert_eq!(vec![vec![1u8], vec![2u8]], journal.pop_batch(2).unwrap());
+    assert_eq!(2, journal.tail.id);
+
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.pop_batch(5).is_err());
+    assert_eq!(2, journal.tail.id);
+
+    assert_eq!(vec![vec![3u8]], journal.pop_batch(5).unwrap());
+    assert_eq!(3, journal.tail.id);
+    assert_eq!(3, journal.reserved_tail.id);
+    assert!(journal.pop_batch(5).unwrap().is_empty());
+    drop(journal);
+
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert!(journal.is_empty());
+    assert_eq!(3, journal.push(&[4u8]).unwrap());
+  }
+
+  #[test]
+  fn test_defragment() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..6u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.trim_before(2).unwrap();
+    let (first, _) = journal.reserve().unwrap().unwrap();
+    let (second, _) = journal.reserve().unwrap().unwrap();
+    assert!(journal.ack(second).unwrap());
+    assert_eq!((2, 3), (first, second));
+
+    journal.defragment().unwrap();
+    let ids: Vec<u64> = Journal::keys_in(&journal.db, KeyType::Queue, 0, None).map(|key| key.id).collect();
+    assert_eq!(vec![0, 1, 2], ids);
+    assert_eq!(vec![(1, vec![4u8]), (2, vec![5u8])], journal.iter().map(Result::unwrap).collect::<Vec<_>>());
+    assert_eq!(1, journal.attempts(0).unwrap());
+    assert!(journal.ack(0).unwrap());
+    assert_eq!(3, journal.push(&[6u8]).unwrap());
+    drop(journal);
+
+    // a gap left by an interrupted defragment is closed on open
+    {
+      let journal = Journal::open(dir.path()).unwrap();
+      let mut batch = Writebatch::new();
+      batch.delete(Key::new(KeyType::Queue, 1));
+      batch.put(Key::new(KeyType::Queue, 7), &[4u8]);
+      batch.put(Key::new(KeyType::Meta, DEFRAGMENT_META), &encode_u64(1));
+      journal.write(&batch).unwrap();
+    }
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(Some(vec![5u8]), journal.pop().unwrap());
+    assert_eq!(Some(vec![6u8]), journal.pop().unwrap());
+    assert_eq!(Some(vec![4u8]), journal.pop().unwrap());
+    assert_eq!(3, journal.next_id());
+  }
+
+  #[test]
+  fn test_pop_transactional() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+
+    let result = journal.pop_transactional(|data| if data == [1u8] { Err("not now") } else { Ok(()) });
+    assert_eq!(Some(Err("not now")), result.unwrap());
+    assert_eq!(2, journal.len());
+
+    let mut seen = Vec::new();
+    let result = journal.pop_transactional(|data| -> Result<(), ()> { seen.extend_from_slice(data); Ok(()) });
+    assert_eq!(Some(Ok(())), result.unwrap());
+    assert_eq!(vec![1u8], seen);
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+    assert_eq!(None, journal.pop_transactional(|_| -> Result<(), ()> { Ok(()) }).unwrap());
+  }
+
+  #[test]
+  fn test_find_by_key() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push_with_key(b"job-1", &[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    journal.push_with_key(b"job-3", &[3u8]).unwrap();
+
+    assert_eq!(Some(vec![3u8]), journal.find_by_key(b"job-3").unwrap());
+    assert_eq!(Some(vec![1u8]), journal.find_by_key(b"job-1").unwrap());
+    assert_eq!(None, journal.find_by_key(b"job-2").unwrap());
+
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert_eq!(None, journal.find_by_key(b"job-1").unwrap());
+    assert_eq!(1, Journal::keys_in(&journal.db, KeyType::Index, 0, None).count());
+
+    // the index follows entries that move
+    journal.trim_before(2).unwrap();
+    journal.defragment().unwrap();
+    assert_eq!(vec![(0, vec![3u8])], journal.iter().map(Result::unwrap).collect::<Vec<_>>());
+    assert_eq!(Some(vec![3u8]), journal.find_by_key(b"job-3").unwrap());
+  }
+
+  #[test]
+  fn test_debug() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..4u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.reserve().unwrap().unwrap();
+    journal.reserve().unwrap().unwrap();
+    journal.ack(0).unwrap();
+    let debug = format!("{:?}", journal);
+    assert!(debug.contains("head: 4, tail: 2, reserved_tail: 1, len: 2"), "{}", debug);
+    assert!(debug.contains(&format!("{:?}", dir.path())), "{}", debug);
+  }
+
+  #[test]
+  fn test_paranoid_checks() {
+    use std::fs;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.paranoid_checks = true;
+    {
+      let mut journal = Journal::open_with_options(dir.path(), options.clone()).unwrap();
+      journal.push(&[1u8]).unwrap();
+      journal.push(&[2u8]).unwrap();
+      assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    }
+    {
+      let mut journal = Journal::open_with_options(dir.path(), options.clone()).unwrap();
+      assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+      journal.push(&[3u8]).unwrap();
+    }
+
+    // damage the log the entries are recovered from
+    for entry in fs::read_dir(dir.path()).unwrap() {
+      let path = entry.unwrap().path();
+      if path.extension().is_some_and(|extension| extension == "log") {
+        let mut log = fs::read(&path).unwrap();
+        log[12] ^= 0xff;
+        fs::write(&path, log).unwrap();
+      }
+    }
+    match Journal::open_with_options(dir.path(), options) {
+      Err(Error::Database(ref err)) => assert!(!err.to_string().contains("exists"), "{}", err),
+      other => panic!("expected the damage to be reported, got {:?}", other)
+    }
+    // without them, LevelDB drops the damaged writes and opens
+    Journal::open(dir.path()).unwrap();
+  }
+
+  #[test]
+  fn test_health_check() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.health_check().unwrap();
+    assert_eq!(None, Journal::read_cursor(&journal.db, super::HEALTH_META).unwrap());
+
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.health_check().is_err());
+    journal.health_check().unwrap();
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_rebuild_missing_cursors() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let drop_head = |journal: Journal| {
+      let mut batch = Writebatch::new();
+      batch.delete(Key::new(KeyType::Meta, HEAD_META));
+      journal.write(&batch).unwrap();
+    };
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert!(!journal.cursors_rebuilt());
+    for i in 0..3u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.pop().unwrap();
+    drop_head(journal);
+
+    let journal = Journal::open(dir.path()).unwrap();
+    assert!(journal.cursors_rebuilt());
+    assert_eq!(3, journal.next_id());
+    assert_eq!(2, journal.len());
+    drop(journal);
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert!(!journal.cursors_rebuilt());
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    drop_head(journal);
+
+    let mut options = JournalOptions::new();
+    options.require_cursors = true;
+    match Journal::open_with_options(dir.path(), options) {
+      Err(Error::MissingCursors(path)) => assert_eq!(dir.path(), path.as_path()),
+      Err(other) => panic!("expected Error::MissingCursors, got {:?}", other),
+      Ok(_) => panic!("expected Error::MissingCursors, opened the journal")
+    }
+  }
+
+  #[test]
+  fn test_sync_supported() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.require_sync = true;
+    let journal = Journal::open_with_options(dir.path(), options).unwrap();
+    assert!(journal.sync_supported());
+    assert!(!dir.path().join(super::SYNC_PROBE_FILE).exists());
+  }
+
+  #[test]
+  fn test_bounds() {
+    use super::Bounds;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let journal = Journal::open(&dir.path().join("unbounded")).unwrap();
+    assert_eq!(Bounds::Unbounded, journal.bounds());
+
+    let mut options = JournalOptions::new();
+    options.capacity = Some(2);
+    let mut journal = Journal::open_with_options(&dir.path().join("bounded"), options).unwrap();
+    assert_eq!(Bounds::Count(2), journal.bounds());
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    assert!(matches!(journal.push(&[3u8]), Err(Error::Full)));
+  }
+
+  #[test]
+  fn test_capacity_after_out_of_order_acks() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.capacity = Some(3);
+    let mut journal = Journal::open_with_options(dir.path(), options.clone()).unwrap();
+    for i in 0..3u8 {
+      journal.push(&[i]).unwrap();
+    }
+    for _ in 0..3 {
+      journal.reserve().unwrap().unwrap();
+    }
+    assert!(matches!(journal.push(&[3u8]), Err(Error::Full)));
+
+    // the acked entries leave holes behind the oldest one in flight
+    assert!(journal.ack(2).unwrap());
+    assert!(journal.ack(1).unwrap());
+    journal.push(&[3u8]).unwrap();
+    journal.push(&[4u8]).unwrap();
+    assert!(matches!(journal.push(&[5u8]), Err(Error::Full)));
+
+    // the count survives a restart
+    drop(journal);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    assert!(matches!(journal.push(&[5u8]), Err(Error::Full)));
+    assert_eq!(Some(vec![0u8]), journal.pop().unwrap());
+    journal.push(&[5u8]).unwrap();
+  }
+
+  #[test]
+  fn test_open_strict() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let missing = dir.path().join("missing");
+    match Journal::open_strict(&missing, JournalOptions::new()) {
+      Err(Error::Io(ref err)) => assert_eq!(io::ErrorKind::NotFound, err.kind()),
+      Err(other) => panic!("expected a not found error, got {:?}", other),
+      Ok(_) => panic!("expected a not found error, opened a journal")
+    }
+    assert!(!missing.exists());
+
+    {
+      let mut journal = Journal::open(&missing).unwrap();
+      journal.push(&[1u8]).unwrap();
+    }
+    let mut journal = Journal::open_strict(&missing, JournalOptions::new()).unwrap();
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_gaps() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.pop().unwrap();
+    assert_eq!(Vec::<(u64, u64)>::new(), journal.gaps().unwrap());
+
+    // acking in the middle of the reserved block leaves no gap
+    journal.reserve().unwrap().unwrap();
+    journal.reserve().unwrap().unwrap();
+    journal.reserve().unwrap().unwrap();
+    assert!(journal.ack(2).unwrap());
+    assert_eq!(Vec::<(u64, u64)>::new(), journal.gaps().unwrap());
+
+    let mut batch = Writebatch::new();
+    batch.delete(Key::new(KeyType::Queue, 5));
+    batch.delete(Key::new(KeyType::Queue, 6));
+    journal.write(&batch).unwrap();
+    assert_eq!(vec![(5, 6)], journal.gaps().unwrap());
+
+    let mut batch = Writebatch::new();
+    batch.delete(Key::new(KeyType::Queue, 3));
+    journal.write(&batch).unwrap();
+    assert_eq!(vec![(3, 3), (5, 6)], journal.gaps().unwrap());
+  }
+
+  #[test]
+  fn test_compact_range() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    let mut batch = Writebatch::new();
+    for id in 3..6 {
+      journal.delete_entry(&mut batch, id).unwrap();
+    }
+    journal.write(&batch).unwrap();
+
+    journal.compact_range(3, 6);
+    journal.compact_range(6, 3);
+    assert_eq!(vec![(3, 5)], journal.gaps().unwrap());
+    for id in [0u64, 1, 2, 6, 7, 8, 9].iter() {
+      assert_eq!(Some(vec![*id as u8]), journal.get(*id).unwrap());
+    }
+    assert_eq!(Some(vec![0u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_shrink() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.sync_writes = false;
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    let value = [7u8; 100];
+    for _ in 0..10_000 {
+      journal.push(&value).unwrap();
+    }
+    assert_eq!(9_900, journal.pop_batch(9_900).unwrap().len());
+
+    let before = journal.disk_size().unwrap();
+    let reclaimed = journal.shrink().unwrap();
+    let after = journal.disk_size().unwrap();
+    assert_eq!(before - after, reclaimed);
+    assert!(after * 4 < before, "{} bytes before shrinking, {} after", before, after);
+    assert_eq!(100, journal.iter().map(Result::unwrap).count());
   }
 
   #[test]
-  fn test_journal() {
+  fn test_auto_compact_after() {
     let dir = TempDir::new("journal_test").unwrap();
     let mut journal = Journal::open(dir.path()).unwrap();
-    let res = journal.pop();
-    assert!(res.is_none());
-    journal.push(&[1u8]);
-    journal.push(&[2u8]);
-    let res2 = journal.pop();
-    assert!(res2.is_some());
-    assert_eq!(Some(vec![1 as u8]), res2);
-    let res3 = journal.pop();
-    assert!(res3.is_some());
-    assert_eq!(Some(vec![2 as u8]), res3);
-    let res4 = journal.pop();
-    assert!(res4.is_none());
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.set_auto_compact_after(Some(3));
+
+    journal.pop().unwrap();
+    journal.pop().unwrap();
+    assert_eq!(2, journal.consumed);
+    let (id, _) = journal.reserve().unwrap().unwrap();
+    assert!(journal.ack(id).unwrap());
+    assert_eq!(0, journal.consumed);
+
+    journal.pop().unwrap();
+    assert_eq!(1, journal.consumed);
+    journal.set_auto_compact_after(None);
+    journal.pop().unwrap();
+    assert_eq!(2, journal.consumed);
+  }
+
+  #[test]
+  fn test_format_version() {
+    use leveldb::database::Database;
+    use leveldb::database::kv::KV;
+    use leveldb::options::{Options,WriteOptions};
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let v1 = dir.path().join("v1");
+    {
+      let mut options = Options::new();
+      options.create_if_missing = true;
+      let db: Database<Key> = Database::open_with_comparator(&v1, options, journal_comparator()).unwrap();
+      db.put(WriteOptions::new(), Key::new(KeyType::Queue, 0), &[1u8]).unwrap();
+      db.put(WriteOptions::new(), Key::new(KeyType::Queue, 1), &[2u8]).unwrap();
+    }
+    let mut journal = Journal::open(&v1).unwrap();
+    assert_eq!(1, journal.format_version());
+    journal.migrate().unwrap();
+    assert_eq!(FORMAT_VERSION, journal.format_version());
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+    drop(journal);
+
+    let journal = Journal::open(&v1).unwrap();
+    assert_eq!(FORMAT_VERSION, journal.format_version());
+    assert_eq!(Some(2), Journal::read_cursor(&journal.db, HEAD_META).unwrap());
+    assert_eq!(FORMAT_VERSION, Journal::open(&dir.path().join("new")).unwrap().format_version());
+
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Meta, FORMAT_META), &encode_u64(FORMAT_VERSION + 1));
+    journal.write(&batch).unwrap();
+    drop(journal);
+    match Journal::open(&v1) {
+      Err(Error::UnsupportedFormat(version)) => assert_eq!(FORMAT_VERSION + 1, version),
+      other => panic!("expected UnsupportedFormat, got {:?}", other.err())
+    }
+  }
+
+  #[test]
+  fn test_user_meta() {
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      let mut journal = Journal::open(dir.path()).unwrap();
+      journal.set_user_meta("schema", &[3u8]).unwrap();
+      journal.set_user_meta("producer", b"first").unwrap();
+      journal.set_user_meta("producer", b"second").unwrap();
+      journal.push(&[1u8]).unwrap();
+    }
+
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(Some(vec![3u8]), journal.get_user_meta("schema").unwrap());
+    assert_eq!(Some(b"second".to_vec()), journal.get_user_meta("producer").unwrap());
+    assert_eq!(None, journal.get_user_meta("missing").unwrap());
+    journal.set_user_meta("", &[]).unwrap();
+    assert_eq!(Some(Vec::new()), journal.get_user_meta("").unwrap());
+    assert_eq!(1, journal.len());
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_lifetime_totals() {
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      let mut journal = Journal::open(dir.path()).unwrap();
+      for i in 0..5u8 {
+        journal.push(&[i]).unwrap();
+      }
+      journal.pop().unwrap();
+      journal.pop_batch(2).unwrap();
+      let (id, _) = journal.reserve().unwrap().unwrap();
+      journal.nack(id).unwrap();
+      assert_eq!(5, journal.total_pushed());
+      assert_eq!(3, journal.total_popped());
+    }
+
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(5, journal.total_pushed());
+    assert_eq!(3, journal.total_popped());
+    journal.push(&[5u8]).unwrap();
+    let (id, _) = journal.reserve().unwrap().unwrap();
+    assert!(journal.ack(id).unwrap());
+    journal.pop_transactional(|_| Ok::<(), ()>(())).unwrap();
+    assert_eq!(6, journal.total_pushed());
+    assert_eq!(5, journal.total_popped());
+  }
+
+  #[test]
+  fn test_drain_eta() {
+    use std::f64;
+    use std::time::Duration;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(None, journal.drain_eta(10.0));
+    for i in 0..30u8 {
+      journal.push(&[i]).unwrap();
+    }
+    assert_eq!(Some(Duration::from_secs(3)), journal.drain_eta(10.0));
+    assert_eq!(Some(Duration::from_millis(7500)), journal.drain_eta(4.0));
+    assert_eq!(None, journal.drain_eta(0.0));
+    assert_eq!(None, journal.drain_eta(-1.0));
+    assert_eq!(None, journal.drain_eta(f64::NAN));
+    assert_eq!(None, journal.drain_eta(f64::MIN_POSITIVE));
+  }
+
+  #[test]
+  fn test_lag() {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(None, journal.lag_duration().unwrap());
+    assert_eq!(None, journal.oldest_age().unwrap());
+    journal.push(&[1u8, 2u8]).unwrap();
+    journal.push(&[3u8]).unwrap();
+    sleep(Duration::from_millis(50));
+
+    assert_eq!(2, journal.lag());
+    assert_eq!(3, journal.lag_bytes().unwrap());
+    assert!(journal.lag_duration().unwrap().unwrap() >= Duration::from_millis(50));
+    assert!(journal.oldest_age().unwrap().unwrap() >= Duration::from_millis(50));
+
+    journal.pop().unwrap();
+    assert_eq!(1, journal.lag());
+    assert_eq!(1, journal.lag_bytes().unwrap());
+  }
+
+  #[test]
+  fn test_iter_reserved() {
+    use std::time::SystemTime;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..4u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.reserve().unwrap().unwrap();
+    journal.reserve().unwrap().unwrap();
+    journal.reserve().unwrap().unwrap();
+    assert!(journal.ack(1).unwrap());
+
+    let reserved: Vec<(u64, Vec<u8>, u32)> = journal.iter_reserved().map(Result::unwrap).map(|(id, data, reservation)| {
+      assert!(reservation.deadline > SystemTime::now());
+      (id, data, reservation.attempts)
+    }).collect();
+    assert_eq!(vec![(0, vec![0u8], 1), (2, vec![2u8], 1)], reserved);
+    assert_eq!(vec![(3, vec![3u8])], journal.iter().map(Result::unwrap).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn test_ack_out_of_order() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    for _ in 0..4 {
+      journal.reserve().unwrap().unwrap();
+    }
+    let reserved = |journal: &Journal| -> Vec<u64> {
+      Journal::keys_in(&journal.db, KeyType::Delivery, 0, None).map(|key| key.id).collect()
+    };
+
+    assert!(journal.ack(2).unwrap());
+    assert_eq!(0, journal.reserved_tail.id);
+    assert!(journal.ack(0).unwrap());
+    assert_eq!(1, journal.reserved_tail.id);
+    assert!(journal.ack(1).unwrap());
+    // skips the gap 2 left
+    assert_eq!(3, journal.reserved_tail.id);
+    assert_eq!(vec![3], reserved(&journal));
+
+    journal.compact_reserved();
+    assert_eq!(vec![3], reserved(&journal));
+    assert_eq!(Some(vec![3u8]), journal.get(3).unwrap());
+
+    assert!(journal.ack(3).unwrap());
+    assert_eq!(journal.tail, journal.reserved_tail);
+    assert!(reserved(&journal).is_empty());
+    assert_eq!(Some(vec![4u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_sync_directory() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let path = dir.path().join("journal");
+    let mut options = JournalOptions::new();
+    options.sync_directory = true;
+    {
+      let mut journal = Journal::open_with_options(&path, options.clone()).unwrap();
+      journal.push(&[1u8]).unwrap();
+    }
+    assert!(path.is_dir());
+    let mut journal = Journal::open_with_options(&path, options).unwrap();
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_max_value_size() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.max_value_size = Some(2);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+
+    journal.push(&[1u8, 2u8]).unwrap();
+    match journal.push(&[1u8, 2u8, 3u8]) {
+      Err(Error::ValueTooLarge(3)) => (),
+      other => panic!("expected ValueTooLarge, got {:?}", other)
+    }
+    assert_eq!(1, journal.len());
+    assert_eq!(1, journal.next_id());
+  }
+
+  #[test]
+  fn test_is_durable() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.sync_writes = false;
+    let mut journal = Journal::open_with_options(&dir.path().join("unsynced"), options).unwrap();
+    let id = journal.push(&[1u8]).unwrap();
+    assert!(!journal.is_durable(id));
+    journal.flush().unwrap();
+    assert!(journal.is_durable(id));
+    assert!(!journal.is_durable(id + 1));
+
+    let mut journal = Journal::open(&dir.path().join("synced")).unwrap();
+    let id = journal.push(&[1u8]).unwrap();
+    assert!(journal.is_durable(id));
+
+    let mut options = JournalOptions::new();
+    options.sync_writes = false;
+    options.offload_threshold = Some(4);
+    let mut journal = Journal::open_with_options(&dir.path().join("offloaded"), options).unwrap();
+    let id = journal.push(&[7u8; 50]).unwrap();
+    assert!(!journal.is_durable(id));
+    journal.flush().unwrap();
+    assert!(journal.is_durable(id));
+    assert_eq!(Some(vec![7u8; 50]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_commit() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.reserve().unwrap().unwrap();
+
+    journal.commit(5).unwrap();
+    assert_eq!(None, journal.get(0).unwrap());
+    assert_eq!(None, journal.get(4).unwrap());
+    assert_eq!(5, journal.len());
+    assert!(!journal.ack(0).unwrap());
+    assert_eq!(Some(vec![5u8]), journal.pop().unwrap());
+
+    journal.commit(2).unwrap();
+    assert_eq!(4, journal.len());
+    journal.commit(100).unwrap();
+    assert!(journal.is_empty());
+    assert_eq!(10, journal.next_id());
+  }
+
+  #[test]
+  fn test_offload() {
+    use std::fs;
+    use leveldb::database::kv::KV;
+    use leveldb::options::ReadOptions;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.offload_threshold = Some(4);
+    let large: Vec<u8> = (0..100u8).collect();
+    {
+      let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+      journal.push(&[1u8]).unwrap();
+      journal.push(&large).unwrap();
+      journal.push(&[2u8]).unwrap();
+
+      let raw = journal.db.get(ReadOptions::new(), Key::new(KeyType::Queue, 1)).unwrap().unwrap();
+      assert_eq!(16, raw.len());
+      assert_eq!(large, fs::read(dir.path().join(super::OFFLOAD_FILE)).unwrap());
+      assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    }
+
+    // offloaded values stay readable with offloading turned off
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(Some(large.clone()), journal.get(1).unwrap());
+    assert_eq!(Some(large), journal.pop().unwrap());
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_entry_size() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.offload_threshold = Some(4);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    journal.push(&[1u8; 3]).unwrap();
+    journal.push(&[2u8; 50]).unwrap();
+    journal.push(&[]).unwrap();
+    assert_eq!(Some(3), journal.entry_size(0).unwrap());
+    assert_eq!(Some(50), journal.entry_size(1).unwrap());
+    assert_eq!(Some(0), journal.entry_size(2).unwrap());
+    assert_eq!(None, journal.entry_size(3).unwrap());
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.cipher = Some(Arc::new(XorCipher { key: 0x5a, counter: AtomicUsize::new(0) }));
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    journal.push(&[1u8; 7]).unwrap();
+    assert_eq!(Some(7), journal.entry_size(0).unwrap());
+  }
+
+  #[test]
+  fn test_corrupt_offload_reference() {
+    use std::io;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.offload_threshold = Some(4);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    journal.push(&[0u8; 10]).unwrap();
+    journal.push(&[1u8; 10]).unwrap();
+
+    let mut reference = [0u8; 16];
+    reference[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Queue, 0), &reference);
+    reference[0..8].copy_from_slice(&u64::MAX.to_be_bytes());
+    batch.put(Key::new(KeyType::Queue, 1), &reference);
+    journal.write(&batch).unwrap();
+    for id in 0..2 {
+      match journal.get(id) {
+        Err(Error::Io(ref err)) => assert_eq!(io::ErrorKind::InvalidData, err.kind()),
+        other => panic!("expected InvalidData, got {:?}", other)
+      }
+    }
+    let mut buf = Vec::new();
+    assert!(journal.pop_into(&mut buf).is_err());
+    assert!(journal.peek().is_err());
+    assert_eq!(2, journal.iter().filter(|entry| entry.is_err()).count());
+    assert!(journal.reserve().is_err());
+    assert_eq!(2, journal.len());
+
+    journal.offload = None;
+    match journal.push(&[2u8; 10]) {
+      Err(Error::Io(ref err)) => assert_eq!(io::ErrorKind::NotFound, err.kind()),
+      other => panic!("expected NotFound, got {:?}", other)
+    }
+  }
+
+  #[test]
+  fn test_write_errors_leave_entries() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..3u8 {
+      journal.push(&[i]).unwrap();
+    }
+
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.pop().is_err());
+    assert_eq!(3, journal.len());
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.reserve().is_err());
+    assert_eq!(3, journal.len());
+
+    let (id, data) = journal.reserve().unwrap().unwrap();
+    assert_eq!((0, vec![0u8]), (id, data));
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.ack(id).is_err());
+    journal.failing_writes.store(1, AtomicOrdering::SeqCst);
+    assert!(journal.nack(id).is_err());
+    assert_eq!(1, journal.iter_reserved().count());
+    assert!(journal.ack(id).unwrap());
+    assert_eq!(Some(vec![1u8]), journal.pop().unwrap());
+    assert_eq!(0, journal.redeliver_expired().unwrap());
+    assert_eq!(vec![(2, vec![2u8])], journal.iter().collect::<Result<Vec<_>, _>>().unwrap());
+  }
+
+  #[test]
+  fn test_oversized_nonce() {
+    use std::io;
+    use std::sync::Arc;
+
+    struct LongNonce;
+
+    impl ValueCipher for LongNonce {
+      fn seal(&self, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        (vec![0u8; 300], plaintext.to_vec())
+      }
+
+      fn open(&self, _nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        Some(ciphertext.to_vec())
+      }
+    }
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.cipher = Some(Arc::new(LongNonce));
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    match journal.push(&[1u8]) {
+      Err(Error::Io(ref err)) => assert_eq!(io::ErrorKind::InvalidInput, err.kind()),
+      other => panic!("expected InvalidInput, got {:?}", other)
+    }
     assert_eq!(0, journal.len());
   }
+
+  #[test]
+  fn test_trim_before() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..10u8 {
+      journal.push(&[i]).unwrap();
+    }
+    journal.pop().unwrap();
+    assert_eq!(3, journal.trim_before(4).unwrap());
+    for i in 0..4 {
+      assert_eq!(None, journal.get(i).unwrap());
+    }
+    assert_eq!(Some(vec![5u8]), journal.get(5).unwrap());
+    assert_eq!(6, journal.len());
+    assert_eq!(Some(vec![4u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_pinned_entries_survive_trim() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.capacity = Some(4);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    journal.push(&[0u8]).unwrap();
+    assert_eq!(1, journal.push_pinned(b"schema").unwrap());
+    journal.push(&[2u8]).unwrap();
+    journal.push(&[3u8]).unwrap();
+    assert!(matches!(journal.push(&[4u8]), Err(Error::Full)));
+
+    // making room for more never drops the pinned entry, nor moves it
+    assert_eq!(2, journal.trim_before(3).unwrap());
+    assert_eq!(Some(b"schema".to_vec()), journal.get(1).unwrap());
+    assert_eq!(vec![b"schema".to_vec(), vec![3u8]], journal.peek_all().unwrap());
+    assert_eq!(2, journal.len());
+    journal.push(&[4u8]).unwrap();
+    journal.push(&[5u8]).unwrap();
+    assert!(matches!(journal.push(&[6u8]), Err(Error::Full)));
+    assert_eq!(3, journal.trim_before(journal.next_id()).unwrap());
+    assert_eq!(vec![b"schema".to_vec()], journal.peek_all().unwrap());
+    assert_eq!(Some(b"schema".to_vec()), journal.peek().unwrap());
+    assert_eq!(1, journal.len());
+    assert_eq!(1, journal.tail.id);
+
+    // the holes around it are skipped, after a restart too
+    journal.push(&[6u8]).unwrap();
+    let mut options = JournalOptions::new();
+    options.capacity = Some(4);
+    drop(journal);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    assert_eq!(Some(vec![6u8]), journal.peek_at(1).unwrap());
+    assert_eq!(Some(b"schema".to_vec()), journal.pop().unwrap());
+    assert_eq!(Some(vec![6u8]), journal.pop().unwrap());
+    assert!(journal.is_empty());
+    journal.push_pinned(b"schema").unwrap();
+
+    assert!(!journal.pin(9).unwrap());
+    assert!(journal.unpin(7).unwrap());
+    assert!(!journal.unpin(7).unwrap());
+    assert_eq!(1, journal.trim_before(journal.next_id()).unwrap());
+    assert!(journal.is_empty());
+
+    // reserved entries stay reserved under their ids
+    journal.push(&[8u8]).unwrap();
+    assert!(journal.pin(8).unwrap());
+    assert_eq!(Some((8, vec![8u8])), journal.reserve().unwrap());
+    assert_eq!(0, journal.trim_before(journal.next_id()).unwrap());
+    assert!(journal.ack(8).unwrap());
+    assert_eq!(None, journal.get(8).unwrap());
+  }
+
+  #[test]
+  fn test_extend_reservation() {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    let (id, _) = journal.reserve_with_timeout(Duration::from_millis(50)).unwrap().unwrap();
+    journal.extend_reservation(id, Duration::from_secs(60)).unwrap();
+    match journal.extend_reservation(1, Duration::from_secs(60)) {
+      Err(Error::NotReserved(1)) => (),
+      other => panic!("expected NotReserved, got {:?}", other)
+    }
+    sleep(Duration::from_millis(100));
+
+    assert_eq!(0, journal.redeliver_expired().unwrap());
+    assert!(journal.ack(id).unwrap());
+    match journal.extend_reservation(id, Duration::from_secs(60)) {
+      Err(Error::NotReserved(0)) => (),
+      other => panic!("expected NotReserved, got {:?}", other)
+    }
+  }
+
+  #[test]
+  fn test_reserve_where() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[b'a', 0u8]).unwrap();
+    journal.push_with_key(b"second", &[b'a', 1u8]).unwrap();
+    journal.push(&[b'b', 2u8]).unwrap();
+    journal.push(&[b'a', 3u8]).unwrap();
+
+    assert_eq!(None, journal.reserve_where(|data| data[0] == b'c').unwrap());
+    assert_eq!(Some((2, vec![b'b', 2u8])), journal.reserve_where(|data| data[0] == b'b').unwrap());
+    assert_eq!(1, journal.attempts(2).unwrap());
+    assert_eq!(Some(vec![b'a', 1u8]), journal.find_by_key(b"second").unwrap());
+    assert_eq!(3, journal.len());
+    assert_eq!(vec![0, 1, 3], journal.iter().map(Result::unwrap).map(|(id, _)| id).collect::<Vec<_>>());
+    assert_eq!(vec![2], journal.iter_reserved().map(Result::unwrap).map(|(id, _, _)| id).collect::<Vec<_>>());
+
+    // the skipped entries are still next, in their order
+    assert_eq!(Some(vec![b'a', 0u8]), journal.pop().unwrap());
+    assert_eq!(Some((1, vec![b'a', 1u8])), journal.reserve_where(|data| data[0] == b'a').unwrap());
+    assert!(journal.ack(2).unwrap());
+    assert_eq!(None, journal.get(2).unwrap());
+    assert!(journal.ack(1).unwrap());
+    assert_eq!(Some(vec![b'a', 3u8]), journal.pop().unwrap());
+    assert_eq!(None, journal.pop().unwrap());
+
+    // after a restart the match is ready again under its id
+    journal.push(&[b'a', 4u8]).unwrap();
+    journal.push(&[b'b', 5u8]).unwrap();
+    assert_eq!(Some((5, vec![b'b', 5u8])), journal.reserve_where(|data| data[0] == b'b').unwrap());
+    drop(journal);
+    let journal = Journal::open(dir.path()).unwrap();
+    assert_eq!(vec![4, 5], journal.iter().map(Result::unwrap).map(|(id, _)| id).collect::<Vec<_>>());
+    assert_eq!(1, journal.attempts(5).unwrap());
+  }
+
+  #[test]
+  fn test_reserve_all() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+
+    let reserved = journal.reserve_all().unwrap();
+    assert_eq!((0..5u8).map(|i| (i as u64, vec![i])).collect::<Vec<_>>(), reserved);
+    journal.push(&[5u8]).unwrap();
+    journal.push(&[6u8]).unwrap();
+    assert_eq!(2, journal.len());
+    assert_eq!(1, journal.attempts(4).unwrap());
+
+    for (id, _) in reserved {
+      assert!(journal.ack(id).unwrap());
+    }
+    assert_eq!(vec![(5, vec![5u8]), (6, vec![6u8])], journal.reserve_all().unwrap());
+    assert_eq!(Vec::<(u64, Vec<u8>)>::new(), journal.reserve_all().unwrap());
+  }
+
+  #[test]
+  fn test_with_reserved() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+
+    let result: Result<u8, ()> = journal.with_reserved(|data| Ok(data[0] * 10)).unwrap().unwrap();
+    assert_eq!(Ok(10), result);
+    assert_eq!(None, journal.get(0).unwrap());
+
+    let result: Result<(), &str> = journal.with_reserved(|_| Err("failed")).unwrap().unwrap();
+    assert_eq!(Err("failed"), result);
+    assert_eq!(1, journal.len());
+    assert_eq!(Some((1, vec![2u8])), journal.reserve().unwrap());
+    assert_eq!(2, journal.attempts(1).unwrap());
+    assert!(journal.with_reserved(|_| Ok::<(), ()>(())).unwrap().is_none());
+  }
+
+  #[test]
+  fn test_max_in_flight() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.max_in_flight = Some(2);
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+
+    assert_eq!(Some((0, vec![0u8])), journal.reserve().unwrap());
+    assert_eq!(Some((1, vec![1u8])), journal.reserve().unwrap());
+    assert_eq!(None, journal.reserve().unwrap());
+    assert_eq!(None, journal.reserve_where(|_| true).unwrap());
+    assert!(journal.ack(0).unwrap());
+    assert_eq!(Some((2, vec![2u8])), journal.reserve().unwrap());
+
+    assert!(journal.ack(1).unwrap());
+    assert!(journal.ack(2).unwrap());
+    assert_eq!(vec![(3, vec![3u8]), (4, vec![4u8])], journal.reserve_all().unwrap());
+    journal.push(&[5u8]).unwrap();
+    journal.push(&[6u8]).unwrap();
+    assert!(journal.ack(3).unwrap());
+    assert_eq!(vec![(5, vec![5u8])], journal.reserve_all().unwrap());
+    assert_eq!(Vec::<(u64, Vec<u8>)>::new(), journal.reserve_all().unwrap());
+    assert_eq!(1, journal.len());
+    assert!(journal.ack(4).unwrap());
+    assert_eq!(Some((6, vec![6u8])), journal.reserve().unwrap());
+  }
+
+  #[test]
+  fn test_reserve_id() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+
+    assert_eq!(Some(vec![3u8]), journal.reserve_id(3).unwrap());
+    assert!(journal.is_reserved(3).unwrap());
+    assert_eq!(4, journal.len());
+    assert_eq!(vec![vec![0u8], vec![1u8], vec![2u8], vec![4u8]], journal.peek_all().unwrap());
+    assert_eq!(Some(vec![4u8]), journal.peek_at(3).unwrap());
+    assert_eq!(None, journal.reserve_id(3).unwrap());
+    assert_eq!(None, journal.reserve_id(5).unwrap());
+
+    assert_eq!(Some(vec![4u8]), journal.reserve_id(4).unwrap());
+    assert!(journal.ack(3).unwrap());
+    assert_eq!(Some(vec![0u8]), journal.pop().unwrap());
+    assert_eq!(Some((1, vec![1u8])), journal.reserve().unwrap());
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+    assert_eq!(None, journal.pop().unwrap());
+
+    // popping past the entry in flight moved it into the reserved block
+    assert_eq!(vec![1, 4], journal.iter_reserved().map(Result::unwrap).map(|(id, _, _)| id).collect::<Vec<_>>());
+    assert!(journal.nack(4).unwrap());
+    assert_eq!(Some((4, vec![4u8])), journal.reserve().unwrap());
+
+    // nacked within the reserved block, the entries in flight after it stay
+    // in flight
+    assert!(journal.nack(1).unwrap());
+    assert_eq!(vec![4], journal.iter_reserved().map(Result::unwrap).map(|(id, _, _)| id).collect::<Vec<_>>());
+    assert_eq!(Some(vec![1u8]), journal.get(1).unwrap());
+    assert_eq!(Some((1, vec![1u8])), journal.reserve().unwrap());
+    assert_eq!(None, journal.reserve().unwrap());
+    assert_eq!(vec![1, 4], journal.iter_reserved().map(Result::unwrap).map(|(id, _, _)| id).collect::<Vec<_>>());
+    assert!(journal.ack(4).unwrap());
+    assert!(journal.ack(1).unwrap());
+    assert!(journal.is_empty());
+  }
+
+  #[test]
+  fn test_reserve_with_timeout() {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    assert_eq!(Some((0, vec![1u8])), journal.reserve_with_timeout(Duration::from_secs(60)).unwrap());
+    assert_eq!(Some((1, vec![2u8])), journal.reserve_with_timeout(Duration::from_millis(20)).unwrap());
+    sleep(Duration::from_millis(50));
+
+    // ready again under its id
+    assert_eq!(1, journal.redeliver_expired().unwrap());
+    assert_eq!(Some((1, vec![2u8])), journal.reserve().unwrap());
+    assert_eq!(2, journal.attempts(1).unwrap());
+    assert_eq!(1, journal.attempts(0).unwrap());
+    assert!(journal.ack(0).unwrap());
+  }
+
+  #[test]
+  fn test_contents_eq() {
+    let first_dir = TempDir::new("journal_test").unwrap();
+    let second_dir = TempDir::new("journal_test").unwrap();
+    let mut first = Journal::open(first_dir.path()).unwrap();
+    let mut second = Journal::open(second_dir.path()).unwrap();
+    assert!(first.contents_eq(&second).unwrap());
+    for i in 0..3u8 {
+      first.push(&[i]).unwrap();
+    }
+    second.push(&[9u8]).unwrap();
+    second.pop().unwrap();
+    for i in 0..3u8 {
+      second.push(&[i]).unwrap();
+    }
+    assert!(first.contents_eq(&second).unwrap());
+    assert!(!first.contents_eq_with_ids(&second).unwrap());
+
+    second.push(&[3u8]).unwrap();
+    assert!(!first.contents_eq(&second).unwrap());
+    assert!(!second.contents_eq(&first).unwrap());
+    first.push(&[4u8]).unwrap();
+    assert!(!first.contents_eq(&second).unwrap());
+  }
+
+  #[test]
+  fn test_prune_older_than() {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    journal.push(&[3u8]).unwrap();
+    journal.reserve().unwrap().unwrap();
+    sleep(Duration::from_millis(100));
+    journal.push(&[4u8]).unwrap();
+
+    assert_eq!(0, journal.prune_older_than(Duration::from_secs(60)).unwrap());
+    assert_eq!(3, journal.prune_older_than(Duration::from_millis(50)).unwrap());
+    assert_eq!(None, journal.get(2).unwrap());
+    assert_eq!(1, journal.len());
+    assert_eq!(Some(vec![4u8]), journal.pop().unwrap());
+  }
+
+  /// Not a real cipher: XORs with the key and a per-call counter, and
+  /// appends a checksum byte so tampering is noticed.
+  struct XorCipher {
+    key: u8,
+    counter: std::sync::atomic::AtomicUsize
+  }
+
+  impl ValueCipher for XorCipher {
+    fn seal(&self, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
+      let nonce = self.counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) as u8;
+      let mut ciphertext: Vec<u8> = plaintext.iter().map(|b| b ^ self.key ^ nonce).collect();
+      ciphertext.push(plaintext.iter().fold(self.key, |sum, b| sum.wrapping_add(*b)));
+      (vec![nonce], ciphertext)
+    }
+
+    fn open(&self, nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+      let (checksum, data) = ciphertext.split_last()?;
+      let plaintext: Vec<u8> = data.iter().map(|b| b ^ self.key ^ nonce[0]).collect();
+      if plaintext.iter().fold(self.key, |sum, b| sum.wrapping_add(*b)) == *checksum {
+        Some(plaintext)
+      } else {
+        None
+      }
+    }
+  }
+
+  #[test]
+  fn test_skip_corrupt_entries() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use super::CorruptEntryPolicy;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.cipher = Some(Arc::new(XorCipher { key: 0x5a, counter: AtomicUsize::new(0) }));
+    options.corrupt_entry_policy = CorruptEntryPolicy::SkipAndDelete;
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    // a nonce longer than the whole entry
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Queue, 1), &[200u8]);
+    batch.put(Key::new(KeyType::Queue, 3), &[200u8]);
+    journal.write(&batch).unwrap();
+
+    assert_eq!(Some(vec![0u8]), journal.pop().unwrap());
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+    assert_eq!(Some((4, vec![4u8])), journal.reserve().unwrap());
+    assert_eq!(None, journal.get(3).unwrap());
+    assert_eq!(2, journal.metrics().errors);
+    assert_eq!(None, journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_verify_all() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.cipher = Some(Arc::new(XorCipher { key: 0x5a, counter: AtomicUsize::new(0) }));
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    for i in 0..5u8 {
+      journal.push(&[i]).unwrap();
+    }
+    assert_eq!(Some((0, vec![0u8])), journal.reserve().unwrap());
+    assert_eq!(Vec::<u64>::new(), journal.verify_all().unwrap());
+
+    // a bad checksum on the reserved entry, a nonce longer than the entry on
+    // a ready one
+    let mut batch = Writebatch::new();
+    batch.put(Key::new(KeyType::Queue, 0), &[1u8, 0, 0, 0]);
+    batch.put(Key::new(KeyType::Queue, 3), &[200u8]);
+    journal.write(&batch).unwrap();
+    assert_eq!(vec![0, 3], journal.verify_all().unwrap());
+    assert_eq!(4, journal.len());
+  }
+
+  #[test]
+  fn test_cipher() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use leveldb::database::kv::KV;
+    use leveldb::options::ReadOptions;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.cipher = Some(Arc::new(XorCipher { key: 0x5a, counter: AtomicUsize::new(0) }));
+    {
+      let mut journal = Journal::open_with_options(dir.path(), options.clone()).unwrap();
+      journal.push(b"secret").unwrap();
+      let raw = journal.db.get(ReadOptions::new(), Key::new(KeyType::Queue, 0)).unwrap().unwrap();
+      assert!(!raw.windows(6).any(|window| window == b"secret"));
+      assert_eq!(Some(b"secret".to_vec()), journal.get(0).unwrap());
+    }
+
+    let mut wrong_key = JournalOptions::new();
+    wrong_key.cipher = Some(Arc::new(XorCipher { key: 0x17, counter: AtomicUsize::new(0) }));
+    {
+      let journal = Journal::open_with_options(dir.path(), wrong_key).unwrap();
+      match journal.get(0) {
+        Err(Error::Decryption(0)) => (),
+        other => panic!("expected a decryption error, got {:?}", other)
+      }
+    }
+
+    let mut journal = Journal::open_with_options(dir.path(), options).unwrap();
+    assert_eq!(Some(b"secret".to_vec()), journal.pop().unwrap());
+  }
 }
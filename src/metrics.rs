@@ -0,0 +1,113 @@
+//! Counting operations for monitoring.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Journal, Operation};
+
+/// How many operations a journal did, see `Journal::metrics`.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub struct JournalMetrics {
+  pub pushes: u64,
+  pub pops: u64,
+  pub reserves: u64,
+  pub acks: u64,
+  pub nacks: u64,
+  /// Failed reads and writes, the ones reported as `Operation::Error`.
+  pub errors: u64,
+  /// The size of all values pushed, in bytes.
+  pub pushed_bytes: u64,
+  /// The size of all values popped, in bytes.
+  pub popped_bytes: u64
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+  pushes: AtomicU64,
+  pops: AtomicU64,
+  reserves: AtomicU64,
+  acks: AtomicU64,
+  nacks: AtomicU64,
+  errors: AtomicU64,
+  pushed_bytes: AtomicU64,
+  popped_bytes: AtomicU64
+}
+
+impl Counters {
+  pub(crate) fn record(&self, operation: Operation, size: usize) {
+    let counter = match operation {
+      Operation::Push => {
+        self.pushed_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        &self.pushes
+      },
+      Operation::Pop => {
+        self.popped_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        &self.pops
+      },
+      Operation::Reserve => &self.reserves,
+      Operation::Ack => &self.acks,
+      Operation::Nack => &self.nacks,
+      Operation::Error => &self.errors
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  // reads every counter with `read`, which may reset it
+  fn collect<F: Fn(&AtomicU64) -> u64>(&self, read: F) -> JournalMetrics {
+    JournalMetrics {
+      pushes: read(&self.pushes),
+      pops: read(&self.pops),
+      reserves: read(&self.reserves),
+      acks: read(&self.acks),
+      nacks: read(&self.nacks),
+      errors: read(&self.errors),
+      pushed_bytes: read(&self.pushed_bytes),
+      popped_bytes: read(&self.popped_bytes)
+    }
+  }
+}
+
+impl Journal {
+  /// The operations counted since the journal was opened, or since the
+  /// last `take_metrics`.
+  pub fn metrics(&self) -> JournalMetrics {
+    self.counters.collect(|counter| counter.load(Ordering::Relaxed))
+  }
+
+  /// Like `metrics`, but resets the counters to zero. Every counter is
+  /// swapped out on its own, so an operation that happens meanwhile is
+  /// counted either in this snapshot or the next, never lost. A snapshot
+  /// may catch an operation in some counters and not yet in others.
+  pub fn take_metrics(&self) -> JournalMetrics {
+    self.counters.collect(|counter| counter.swap(0, Ordering::Relaxed))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use self::tempdir::TempDir;
+  use super::JournalMetrics;
+  use super::super::Journal;
+
+  #[test]
+  fn test_take_metrics() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8, 2u8]).unwrap();
+    journal.push(&[3u8]).unwrap();
+    journal.push(&[4u8]).unwrap();
+    journal.pop().unwrap();
+    let (id, _) = journal.reserve().unwrap().unwrap();
+    journal.ack(id).unwrap();
+
+    let expected = JournalMetrics { pushes: 3, pops: 1, reserves: 1, acks: 1, pushed_bytes: 4, popped_bytes: 2, ..JournalMetrics::default() };
+    assert_eq!(expected, journal.metrics());
+    assert_eq!(expected, journal.take_metrics());
+    assert_eq!(JournalMetrics::default(), journal.metrics());
+
+    journal.pop().unwrap();
+    let expected = JournalMetrics { pops: 1, popped_bytes: 1, ..JournalMetrics::default() };
+    assert_eq!(expected, journal.take_metrics());
+  }
+}
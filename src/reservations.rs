@@ -0,0 +1,134 @@
+//! Reserving entries in a worker loop without leaking them on early exits.
+
+use std::ops::Deref;
+
+use super::{Error, Id, Journal};
+
+/// Hands out reserved entries one at a time, see `Journal::reservations`.
+pub struct Reservations<'a> {
+  journal: &'a mut Journal
+}
+
+/// A reserved entry that is nacked when dropped, unless it was acked.
+/// Derefs to the entry's value.
+pub struct ReservedEntry<'a> {
+  journal: &'a mut Journal,
+  id: Id,
+  data: Vec<u8>,
+  settled: bool
+}
+
+impl Journal {
+  /// Reserves entries one after the other, each wrapped in a guard that
+  /// nacks it when dropped without `ack`, like on a panic or an early
+  /// return in the loop handling it.
+  ///
+  /// Every guard borrows the journal, so there is one entry in flight at a
+  /// time; the next is only reserved once the previous guard is gone.
+  pub fn reservations(&mut self) -> Reservations<'_> {
+    Reservations { journal: self }
+  }
+}
+
+impl<'a> Reservations<'a> {
+  /// Reserves the entry at the tail, None if there is none, or the error
+  /// reserving it failed with.
+  // not an Iterator, entries borrow the journal from it
+  #[allow(clippy::should_implement_trait)]
+  pub fn next(&mut self) -> Option<Result<ReservedEntry<'_>, Error>> {
+    match self.journal.reserve() {
+      Ok(Some((id, data))) => Some(Ok(ReservedEntry { journal: &mut *self.journal, id, data, settled: false })),
+      Ok(None) => None,
+      Err(err) => Some(Err(err))
+    }
+  }
+}
+
+impl<'a> ReservedEntry<'a> {
+  pub fn id(&self) -> Id {
+    self.id
+  }
+
+  /// Removes the entry for good, see `Journal::ack`.
+  pub fn ack(mut self) -> Result<bool, Error> {
+    self.settled = true;
+    self.journal.ack(self.id)
+  }
+
+  /// Gives up on the entry right away, see `Journal::nack`.
+  pub fn nack(mut self) -> Result<bool, Error> {
+    self.settled = true;
+    self.journal.nack(self.id)
+  }
+}
+
+impl<'a> Deref for ReservedEntry<'a> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.data
+  }
+}
+
+impl<'a> Drop for ReservedEntry<'a> {
+  fn drop(&mut self) {
+    // a failed nack was reported to the observer already, drop has no
+    // way to return it
+    if !self.settled {
+      let _ = self.journal.nack(self.id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use self::tempdir::TempDir;
+  use super::super::Journal;
+
+  #[test]
+  fn test_reservations() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.push(&[2u8]).unwrap();
+    {
+      let mut reservations = journal.reservations();
+      let entry = reservations.next().unwrap().unwrap();
+      assert_eq!(0, entry.id());
+      assert_eq!(&[1u8][..], &*entry);
+    }
+    // dropped without an ack, so it is back in the queue
+    assert_eq!(2, journal.len());
+    assert_eq!(1, journal.attempts(0).unwrap());
+
+    let mut reservations = journal.reservations();
+    let entry = reservations.next().unwrap().unwrap();
+    assert_eq!((0, &[1u8][..]), (entry.id(), &*entry));
+    assert!(entry.ack().unwrap());
+    let entry = reservations.next().unwrap().unwrap();
+    assert_eq!((1, &[2u8][..]), (entry.id(), &*entry));
+    assert!(entry.ack().unwrap());
+    assert!(reservations.next().is_none());
+    assert!(journal.is_empty());
+  }
+
+  #[test]
+  fn test_reservations_yield_errors() {
+    use std::sync::atomic::Ordering;
+
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push(&[1u8]).unwrap();
+    journal.failing_writes.store(1, Ordering::SeqCst);
+    {
+      let mut reservations = journal.reservations();
+      assert!(reservations.next().unwrap().is_err());
+      let entry = reservations.next().unwrap().unwrap();
+      assert_eq!((0, &[1u8][..]), (entry.id(), &*entry));
+      assert!(entry.ack().unwrap());
+    }
+    assert!(journal.is_empty());
+  }
+}
@@ -0,0 +1,203 @@
+//! Packing small entries together, so they share a single record.
+//!
+//! Every entry costs a LevelDB record of its own, and its timestamp another,
+//! which for tiny values outweighs the values themselves. A
+//! `SegmentedJournal` stores many entries as one journal entry, a segment,
+//! and splits them out again when they are read.
+//!
+//! This only pays off for appending and popping in order. A push rewrites
+//! the newest segment with the entry appended, so segments should be kept
+//! small, and a segment keeps all its bytes until its last entry is popped.
+//! Taking a single entry out of the middle, as acking, nacking or trimming
+//! do, would rewrite its whole segment, so none of that is offered here.
+
+use std::io;
+
+use leveldb::database::batch::Writebatch;
+use leveldb::database::kv::KV;
+use leveldb::options::ReadOptions;
+
+use super::{decode_u64, encode_u64, Error, Id, Journal, Key, KeyType, SEGMENT_META};
+
+/// Length of the header in front of every entry in a segment, its length as
+/// an 8 byte big-endian integer.
+const FRAME_HEADER: usize = 8;
+
+/// A journal packing the entries pushed through it into segments of up to
+/// `segment_size` bytes. `pop` hands them out one at a time, oldest first,
+/// and stores how far into the oldest segment it got, so it carries on
+/// there after a restart.
+///
+/// The journal underneath holds segments, not entries, so its `len`,
+/// `JournalOptions::capacity` and the like count segments.
+pub struct SegmentedJournal {
+  journal: Journal,
+  segment_size: usize
+}
+
+impl SegmentedJournal {
+  /// Packs the entries pushed into `journal`, which should only be written
+  /// to through this from then on. An entry too large to share a segment
+  /// gets one of its own.
+  pub fn new(journal: Journal, segment_size: usize) -> SegmentedJournal {
+    SegmentedJournal { journal, segment_size }
+  }
+
+  /// Appends `data` to the newest segment, or starts a new one if it does
+  /// not fit. Returns the id of the segment it went into.
+  pub fn push(&mut self, data: &[u8]) -> Result<Id, Error> {
+    let mut frame = encode_u64(data.len() as u64).to_vec();
+    frame.extend_from_slice(data);
+    let journal = &mut self.journal;
+    if let Some((key, stored)) = journal.ready_rev().next() {
+      // appending anywhere but to the entry at the head would reorder
+      if key.id + 1 == journal.head.id {
+        let mut segment = journal.decode_value(key.id, stored)?;
+        if segment.len() + frame.len() <= self.segment_size {
+          segment.extend_from_slice(&frame);
+          journal.replace_value(key.id, &segment)?;
+          return Ok(key.id)
+        }
+      }
+    }
+    journal.push(&frame)
+  }
+
+  /// Removes and returns the oldest entry. The segment holding it is popped
+  /// along with its last entry.
+  pub fn pop(&mut self) -> Result<Option<Vec<u8>>, Error> {
+    let (id, segment, popped) = match self.oldest()? {
+      Some(oldest) => oldest,
+      None => return Ok(None)
+    };
+    let entries = split(id, &segment, popped)?;
+    let data = entries[popped].to_vec();
+    if popped + 1 == entries.len() {
+      self.journal.pop()?;
+    } else {
+      let mut record = encode_u64(id).to_vec();
+      record.extend_from_slice(&encode_u64(popped as u64 + 1));
+      let mut batch = Writebatch::new();
+      batch.put(Key::new(KeyType::Meta, SEGMENT_META), &record);
+      self.journal.write(&batch).map_err(|err| self.journal.write_error(id, err))?;
+    }
+    Ok(Some(data))
+  }
+
+  /// Returns the entry `pop` would return, without removing it.
+  pub fn peek(&self) -> Result<Option<Vec<u8>>, Error> {
+    match self.oldest()? {
+      Some((id, segment, popped)) => Ok(Some(split(id, &segment, popped)?[popped].to_vec())),
+      None => Ok(None)
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.journal.is_empty()
+  }
+
+  /// The journal holding the segments.
+  pub fn journal(&self) -> &Journal {
+    &self.journal
+  }
+
+  pub fn into_inner(self) -> Journal {
+    self.journal
+  }
+
+  // the oldest segment, with how many of its entries were popped already
+  fn oldest(&self) -> Result<Option<(Id, Vec<u8>, usize)>, Error> {
+    let (key, stored) = match self.journal.ready().next() {
+      Some(next) => next,
+      None => return Ok(None)
+    };
+    let segment = self.journal.decode_value(key.id, stored)?;
+    let read_options = ReadOptions::new();
+    let record = self.journal.db.get(read_options, Key::new(KeyType::Meta, SEGMENT_META))
+      .map_err(|err| self.journal.read_error(key.id, err))?;
+    // a record left of a segment that is gone since does not count
+    let popped = match record {
+      Some(ref record) if record.len() == 16 && decode_u64(&record[..8]) == Some(key.id) => {
+        decode_u64(&record[8..]).unwrap_or(0) as usize
+      },
+      _ => 0
+    };
+    Ok(Some((key.id, segment, popped)))
+  }
+}
+
+/// Splits the segment `id` into its entries. Fails with an `Error::Io` of
+/// kind `InvalidData` if it does not hold more than `popped` whole ones.
+fn split(id: Id, mut segment: &[u8], popped: usize) -> Result<Vec<&[u8]>, Error> {
+  let mut entries = Vec::new();
+  while !segment.is_empty() {
+    let len = match segment.get(..FRAME_HEADER).and_then(decode_u64) {
+      Some(len) if len <= (segment.len() - FRAME_HEADER) as u64 => len as usize,
+      _ => return Err(malformed(id))
+    };
+    entries.push(&segment[FRAME_HEADER..FRAME_HEADER + len]);
+    segment = &segment[FRAME_HEADER + len..];
+  }
+  if entries.len() <= popped {
+    return Err(malformed(id))
+  }
+  Ok(entries)
+}
+
+fn malformed(id: Id) -> Error {
+  Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("segment {} is malformed", id)))
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use self::tempdir::TempDir;
+  use super::SegmentedJournal;
+  use super::super::{Journal, KeyType};
+
+  #[test]
+  fn test_segments_pack_entries() {
+    let dir = TempDir::new("journal_test").unwrap();
+    {
+      // nine bytes to an entry with its header, seven entries to a segment
+      let mut segments = SegmentedJournal::new(Journal::open(dir.path()).unwrap(), 64);
+      for i in 0..100u8 {
+        assert_eq!(u64::from(i / 7), segments.push(&[i]).unwrap());
+      }
+      assert_eq!(15, segments.journal().len());
+      assert_eq!(15, Journal::keys_in(&segments.journal().db, KeyType::Queue, 0, None).count());
+      for i in 0..10u8 {
+        assert_eq!(Some(vec![i]), segments.pop().unwrap());
+      }
+      assert_eq!(14, segments.journal().len());
+    }
+
+    let mut segments = SegmentedJournal::new(Journal::open(dir.path()).unwrap(), 64);
+    assert_eq!(Some(vec![10u8]), segments.peek().unwrap());
+    for i in 10..100u8 {
+      assert_eq!(Some(vec![i]), segments.pop().unwrap());
+    }
+    assert_eq!(None, segments.pop().unwrap());
+    assert!(segments.is_empty());
+  }
+
+  #[test]
+  fn test_segments_large_entries() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut segments = SegmentedJournal::new(Journal::open(dir.path()).unwrap(), 32);
+    assert_eq!(0, segments.push(&[1]).unwrap());
+    assert_eq!(1, segments.push(&[2; 32]).unwrap());
+    assert_eq!(2, segments.push(&[3]).unwrap());
+    assert_eq!(2, segments.push(&[4]).unwrap());
+    assert_eq!(Some(vec![1u8]), segments.pop().unwrap());
+    assert_eq!(Some(vec![2u8; 32]), segments.pop().unwrap());
+    assert_eq!(Some(vec![3u8]), segments.pop().unwrap());
+
+    // the partly popped segment is still appended to
+    assert_eq!(2, segments.push(&[5]).unwrap());
+    assert_eq!(Some(vec![4u8]), segments.pop().unwrap());
+    assert_eq!(Some(vec![5u8]), segments.pop().unwrap());
+    assert!(segments.is_empty());
+  }
+}
@@ -0,0 +1,236 @@
+//! Sharing a journal between threads.
+//!
+//! Reading methods take `&self` and LevelDB serves reads from any number of
+//! threads, so a `SharedJournal` lets readers in together and only makes
+//! writers wait for everyone else.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use super::{Error, Id, Journal};
+
+/// A journal behind a lock, for producers and consumers on different
+/// threads. Any number of readers can hold it at once, writers get it to
+/// themselves. Producers can wait for a bounded journal to free up space
+/// with `push_blocking`, or for the journal to drain with
+/// `wait_until_empty`.
+pub struct SharedJournal {
+  journal: RwLock<Journal>,
+  changes: Mutex<u64>, // bumped whenever a writer lets go of the journal
+  changed: Condvar
+}
+
+/// Access to the journal inside a `SharedJournal` for any operation on it.
+/// Producers waiting in `push_blocking` check for space again once it is
+/// dropped.
+pub struct SharedGuard<'a> {
+  guard: RwLockWriteGuard<'a, Journal>,
+  shared: &'a SharedJournal
+}
+
+/// Access to the journal inside a `SharedJournal` for reading only, shared
+/// with other readers.
+pub struct SharedReadGuard<'a> {
+  guard: RwLockReadGuard<'a, Journal>
+}
+
+impl SharedJournal {
+  pub fn new(journal: Journal) -> SharedJournal {
+    SharedJournal { journal: RwLock::new(journal), changes: Mutex::new(0), changed: Condvar::new() }
+  }
+
+  /// Locks the journal for any operation on it, waiting for all readers.
+  pub fn lock(&self) -> SharedGuard<'_> {
+    SharedGuard { guard: self.write(), shared: self }
+  }
+
+  /// Locks the journal for reading, `peek`, `iter`, `get` and the like,
+  /// alongside other readers.
+  pub fn read(&self) -> SharedReadGuard<'_> {
+    let guard = self.journal.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    SharedReadGuard { guard }
+  }
+
+  /// Like `Journal::push`, but waits while the journal is at its
+  /// `JournalOptions::capacity` instead of failing with `Error::Full`.
+  /// Gives up with `Error::TimedOut` after `timeout`, if there is one.
+  pub fn push_blocking(&self, data: &[u8], timeout: Option<Duration>) -> Result<Id, Error> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+      // taken before trying, so a writer finishing in between is noticed
+      let seen = *self.changes();
+      match self.write().push(data) {
+        Err(Error::Full) => (),
+        result => return result
+      }
+      self.wait_for_change(seen, deadline)?;
+    }
+  }
+
+  /// Waits until no entries are ready to be popped, for a producer to let
+  /// the consumers drain the journal before shutting down. Entries still
+  /// reserved do not count. Gives up with `Error::TimedOut` after
+  /// `timeout`, if there is one.
+  pub fn wait_until_empty(&self, timeout: Option<Duration>) -> Result<(), Error> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+      let seen = *self.changes();
+      if self.read().is_empty() {
+        return Ok(())
+      }
+      self.wait_for_change(seen, deadline)?;
+    }
+  }
+
+  pub fn into_inner(self) -> Journal {
+    self.journal.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  fn write(&self) -> RwLockWriteGuard<'_, Journal> {
+    self.journal.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  fn changes(&self) -> MutexGuard<'_, u64> {
+    self.changes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  // waits for a writer to let go of the journal after `seen` was read
+  fn wait_for_change(&self, seen: u64, deadline: Option<Instant>) -> Result<(), Error> {
+    let mut changes = self.changes();
+    while *changes == seen {
+      changes = match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            return Err(Error::TimedOut)
+          }
+          let waited = self.changed.wait_timeout(changes, deadline - now);
+          waited.unwrap_or_else(|poisoned| poisoned.into_inner()).0
+        },
+        None => self.changed.wait(changes).unwrap_or_else(|poisoned| poisoned.into_inner())
+      };
+    }
+    Ok(())
+  }
+}
+
+impl<'a> Deref for SharedGuard<'a> {
+  type Target = Journal;
+
+  fn deref(&self) -> &Journal {
+    &self.guard
+  }
+}
+
+impl<'a> DerefMut for SharedGuard<'a> {
+  fn deref_mut(&mut self) -> &mut Journal {
+    &mut self.guard
+  }
+}
+
+impl<'a> Drop for SharedGuard<'a> {
+  fn drop(&mut self) {
+    *self.shared.changes() += 1;
+    self.shared.changed.notify_all();
+  }
+}
+
+impl<'a> Deref for SharedReadGuard<'a> {
+  type Target = Journal;
+
+  fn deref(&self) -> &Journal {
+    &self.guard
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+  use self::tempdir::TempDir;
+  use super::SharedJournal;
+  use super::super::{Error, Journal, JournalOptions};
+
+  #[test]
+  fn test_push_blocking() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut options = JournalOptions::new();
+    options.capacity = Some(2);
+    let shared = Arc::new(SharedJournal::new(Journal::open_with_options(dir.path(), options).unwrap()));
+    shared.lock().push(&[1u8]).unwrap();
+    shared.lock().push(&[2u8]).unwrap();
+    match shared.push_blocking(&[3u8], Some(Duration::from_millis(10))) {
+      Err(Error::TimedOut) => (),
+      other => panic!("expected TimedOut, got {:?}", other)
+    }
+
+    let producer = {
+      let shared = shared.clone();
+      thread::spawn(move || shared.push_blocking(&[3u8], None))
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(Some(vec![1u8]), shared.lock().pop().unwrap());
+    assert_eq!(2, producer.join().unwrap().unwrap());
+
+    let mut journal = Arc::try_unwrap(shared).ok().unwrap().into_inner();
+    assert_eq!(Some(vec![2u8]), journal.pop().unwrap());
+    assert_eq!(Some(vec![3u8]), journal.pop().unwrap());
+  }
+
+  #[test]
+  fn test_wait_until_empty() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let shared = Arc::new(SharedJournal::new(Journal::open(dir.path()).unwrap()));
+    for i in 0..10u8 {
+      shared.lock().push(&[i]).unwrap();
+    }
+    match shared.wait_until_empty(Some(Duration::from_millis(10))) {
+      Err(Error::TimedOut) => (),
+      other => panic!("expected TimedOut, got {:?}", other)
+    }
+
+    let consumer = {
+      let shared = shared.clone();
+      thread::spawn(move || {
+        for i in 0..10u8 {
+          thread::sleep(Duration::from_millis(2));
+          assert_eq!(Some(vec![i]), shared.lock().pop().unwrap());
+        }
+      })
+    };
+    shared.wait_until_empty(None).unwrap();
+    assert!(shared.read().is_empty());
+    consumer.join().unwrap();
+  }
+
+  #[test]
+  fn test_concurrent_readers() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let shared = Arc::new(SharedJournal::new(Journal::open(dir.path()).unwrap()));
+    let readers: Vec<_> = (0..4).map(|_| {
+      let shared = shared.clone();
+      thread::spawn(move || {
+        for _ in 0..50 {
+          let journal = shared.read();
+          let entries: Vec<_> = journal.iter().map(Result::unwrap).collect();
+          assert_eq!(journal.len(), entries.len() as u64);
+          assert_eq!(journal.peek().unwrap(), entries.first().map(|entry| entry.1.clone()));
+          for (id, data) in entries {
+            assert_eq!(vec![id as u8], data);
+          }
+        }
+      })
+    }).collect();
+    for i in 0..100u8 {
+      shared.lock().push(&[i]).unwrap();
+    }
+    for reader in readers {
+      reader.join().unwrap();
+    }
+    assert_eq!(100, shared.read().len());
+  }
+}
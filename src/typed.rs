@@ -0,0 +1,142 @@
+//! Pushing and reading values of your own types instead of raw bytes.
+//!
+//! The journal does not pick a serialization format. Implement `Encode` and
+//! `Decode` for your types over whatever format you use.
+
+use std::error;
+
+use super::{Error, Id, Journal};
+
+/// Turns a value into the bytes stored in the journal.
+pub trait Encode {
+  fn encode(&self) -> Vec<u8>;
+}
+
+/// Reads a value back from the bytes stored in the journal.
+pub trait Decode: Sized {
+  fn decode(data: &[u8]) -> Result<Self, Box<dyn error::Error + Send + Sync>>;
+}
+
+impl Journal {
+  /// Encodes `value` and pushes it, returning the id it was written under.
+  pub fn push_typed<T: Encode>(&mut self, value: &T) -> Result<Id, Error> {
+    self.push(&value.encode())
+  }
+
+  /// Decodes the entry `pop` would return next, without popping it.
+  ///
+  /// Fails with `Error::Decode` if the entry does not decode, and with the
+  /// same errors as `peek_cow` if it can not be read.
+  pub fn peek_typed<T: Decode>(&self) -> Result<Option<T>, Error> {
+    match self.ready().next() {
+      Some((key, stored)) => {
+        let data = self.decode_value(key.id, stored)?;
+        T::decode(&data).map(Some).map_err(|err| Error::Decode(key.id, err))
+      },
+      None => Ok(None)
+    }
+  }
+
+  /// Like `iter`, but decodes every entry as it is reached.
+  ///
+  /// An entry that fails to be read or decoded yields an error and
+  /// iteration carries on with the next one.
+  pub fn iter_typed<T: Decode>(&self) -> impl Iterator<Item = Result<(Id, T), Error>> + '_ {
+    self.iter().map(|entry| {
+      let (id, data) = entry?;
+      T::decode(&data).map(|value| (id, value)).map_err(|err| Error::Decode(id, err))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use std::error;
+  use self::tempdir::TempDir;
+  use super::{Encode, Decode};
+  use super::super::{Error, Journal};
+
+  #[derive(Debug,PartialEq)]
+  struct Point {
+    x: u32,
+    y: u32
+  }
+
+  impl Encode for Point {
+    fn encode(&self) -> Vec<u8> {
+      let mut result = Vec::with_capacity(8);
+      result.extend_from_slice(&self.x.to_be_bytes());
+      result.extend_from_slice(&self.y.to_be_bytes());
+      result
+    }
+  }
+
+  impl Decode for Point {
+    fn decode(data: &[u8]) -> Result<Point, Box<dyn error::Error + Send + Sync>> {
+      if data.len() != 8 {
+        return Err(format!("expected 8 bytes, got {}", data.len()).into())
+      }
+      let mut x = [0u8; 4];
+      let mut y = [0u8; 4];
+      x.copy_from_slice(&data[0..4]);
+      y.copy_from_slice(&data[4..8]);
+      Ok(Point { x: u32::from_be_bytes(x), y: u32::from_be_bytes(y) })
+    }
+  }
+
+  #[test]
+  fn test_peek_typed() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    assert!(journal.peek_typed::<Point>().unwrap().is_none());
+    journal.push_typed(&Point { x: 1, y: 2 }).unwrap();
+    journal.push(&[1u8, 2u8, 3u8]).unwrap();
+
+    assert_eq!(Some(Point { x: 1, y: 2 }), journal.peek_typed().unwrap());
+    assert_eq!(Some(Point { x: 1, y: 2 }), journal.peek_typed().unwrap());
+    assert_eq!(2, journal.len());
+    journal.pop().unwrap();
+    match journal.peek_typed::<Point>() {
+      Err(Error::Decode(1, _)) => (),
+      other => panic!("expected a decode error for entry 1, got {:?}", other)
+    }
+    assert_eq!(1, journal.len());
+  }
+
+  #[test]
+  fn test_peek_typed_past_reserved_entry() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push_typed(&Point { x: 1, y: 2 }).unwrap();
+    journal.push_typed(&Point { x: 3, y: 4 }).unwrap();
+    journal.push(&[1u8, 2u8, 3u8]).unwrap();
+
+    // the tail ends up on entry 1, which is in flight
+    assert!(journal.reserve_id(1).unwrap().is_some());
+    journal.pop().unwrap();
+    match journal.peek_typed::<Point>() {
+      Err(Error::Decode(2, _)) => (),
+      other => panic!("expected a decode error for entry 2, got {:?}", other)
+    }
+  }
+
+  #[test]
+  fn test_iter_typed() {
+    let dir = TempDir::new("journal_test").unwrap();
+    let mut journal = Journal::open(dir.path()).unwrap();
+    journal.push_typed(&Point { x: 1, y: 2 }).unwrap();
+    journal.push(&[1u8, 2u8, 3u8]).unwrap();
+    journal.push_typed(&Point { x: 3, y: 4 }).unwrap();
+
+    let items: Vec<Result<(u64, Point), Error>> = journal.iter_typed().collect();
+    assert_eq!(3, items.len());
+    assert_eq!(Point { x: 1, y: 2 }, items[0].as_ref().unwrap().1);
+    match items[1] {
+      Err(Error::Decode(1, _)) => (),
+      ref other => panic!("expected a decode error for entry 1, got {:?}", other)
+    }
+    assert_eq!((2, Point { x: 3, y: 4 }), *items[2].as_ref().unwrap());
+  }
+}